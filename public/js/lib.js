@@ -18,6 +18,36 @@ function formatDuration(ms) {
     .join(' ');
 };
 
+/**
+ * Bytes that end-to-end encryption adds to each part, for the IV and the tag.
+ */
+const END_TO_END_PART_OVERHEAD = 12 + 16;
+
+/**
+ * Used to turn the date and time picked for a custom expiration into one the
+ * server can read, since the picker leaves out the time zone.
+ * 
+ * @param {string} localDateTime The value of a `datetime-local` input.
+ * @returns {string} The same date and time in ISO 8601, or nothing if none was picked.
+ */
+function toISOString(localDateTime) {
+    return localDateTime ? new Date(localDateTime).toISOString() : "";
+}
+
+/**
+ * @returns {number} How large each part of a file uploaded in parts is, as configured on the server.
+ */
+function getPartSize() {
+    return Number(document.getElementById("file").dataset.partSize);
+}
+
+/**
+ * The raw key of the end-to-end encrypted upload in progress, if there is one.
+ * 
+ * @type {Uint8Array | null}
+ */
+let endToEndKey = null;
+
 /**
  * Used to configure the parts parameter when the file should be split.
  * 
@@ -27,18 +57,91 @@ function configRequestParts(event) {
     /** @type {FormData} */
     const form = event.detail.parameters;
 
+    const endToEnd = form.has("End-to-end encrypt");
+    // Pasted text is sent as is, and so are several files shared together as
+    // a collection apart from files in a folder being named by their path
+    // within it.
+    /** @type {File[]} */
+    const files = form.getAll("File");
+    if (files.length != 1 || files[0].webkitRelativePath) {
+        if (endToEnd) {
+            alert("End-to-end encryption only works when sharing a single file.");
+            event.preventDefault();
+            return;
+        }
+        if (files.length > 1 && (form.get("Max downloads") || form.get("Password"))) {
+            alert("Max downloads and passwords can only be set when sharing a single file.");
+            event.preventDefault();
+            return;
+        }
+        form.delete("File");
+        for (const file of files) {
+            form.append("File", file, file.webkitRelativePath || file.name);
+        }
+        return;
+    }
+
     /** @type {File} */
     const file = form.get("File");
-    const parts = Math.ceil(file.size / 5000000);
+    const parts = getPartCount(file);
+
+    if (endToEnd && !window.crypto?.subtle) {
+        alert("End-to-end encryption isn't available in this browser, or over an insecure connection.");
+        event.preventDefault();
+        return;
+    }
+    endToEndKey = endToEnd ? crypto.getRandomValues(new Uint8Array(32)) : null;
 
     form.delete("File");
+    form.delete("End-to-end encrypt");
     form.append("Parts", parts);
     form.append("Filename", file.name);
+    if (endToEnd) {
+        // What gets stored is the encrypted parts, which are a bit larger.
+        form.append("Size", file.size + parts * END_TO_END_PART_OVERHEAD);
+        form.append("End-to-end encrypt", "on");
+    } else {
+        form.append("Size", file.size);
+        const fileInput = document.getElementById("file");
+        if (fileInput.dataset.checksum) {
+            form.append("Checksum", fileInput.dataset.checksum);
+        }
+    }
 
     console.log(Array.from(event.detail.parameters.entries()));
 }
 
 
+/**
+ * @param {File} file 
+ * @returns {number} How many parts the file is uploaded in, which is at least one even when it's empty.
+ */
+function getPartCount(file) {
+    return Math.max(1, Math.ceil(file.size / getPartSize()));
+}
+
+
+/**
+ * Slices out a single part of a file that is being uploaded in parts.
+ * 
+ * @param {File} file 
+ * @param {number} part 
+ * @returns {Blob} The part of the file.
+ */
+function getPartOfFile(file, part) {
+    const parts = getPartCount(file);
+    const partSize = Math.ceil(file.size / parts);
+
+    let thisPartSize = partSize;
+    if (part == parts - 1) {
+        thisPartSize = file.size - (partSize * (parts - 1));
+    }
+
+    const partStartOffset = partSize * part;
+    return file.slice(partStartOffset, partStartOffset + thisPartSize, file.type);
+}
+
+
 /**
  * Used to configure the parts parameter when the file should be split.
  * 
@@ -52,19 +155,321 @@ function configPartRequest(event) {
     /** @type {number} */
     const part = form.get("Part");
 
-    const parts = Math.ceil(file.size / 5000000);
-    const partSize = Math.ceil(file.size / parts);
+    if (endToEndKey) {
+        // Never let an end-to-end encrypted file go out unencrypted.
+        const encryptedPart = event.detail.elt.encryptedPart;
+        if (!encryptedPart) {
+            event.preventDefault();
+            return;
+        }
+        form.set("File", encryptedPart);
+    } else {
+        form.set("File", getPartOfFile(file, part));
+    }
 
-    let thisPartSize = partSize;
-    if (part == parts - 1) {
-        thisPartSize = file.size - (partSize * (parts - 1));
+    console.log(Array.from(event.detail.parameters.entries()));
+}
+
+
+/**
+ * Used to encrypt a part if the upload is end-to-end encrypted, and to fill in
+ * the SHA-256 checksum of the part so the server can detect corruption.
+ * Browsers only allow hashing in secure contexts, so outside of those the
+ * checksum is left empty and not checked.
+ * 
+ * @param {HTMLFormElement} partForm 
+ */
+async function preparePart(partForm) {
+    if (!window.crypto?.subtle) {
+        return;
     }
 
-    const partStartOffset = partSize * part;
-    const partOfFile = file.slice(partStartOffset, partStartOffset + thisPartSize, file.type);
-    form.set("File", partOfFile);
+    /** @type {File} */
+    const file = document.getElementById("file").files[0];
+    const part = Number(partForm.querySelector("input[name='Part']").value);
 
-    console.log(Array.from(event.detail.parameters.entries()));
+    let partBlob = getPartOfFile(file, part);
+    if (endToEndKey) {
+        // Encrypted once and kept around, so retries send the same bytes.
+        partBlob = await encryptPart(partBlob, part);
+        partForm.encryptedPart = partBlob;
+    }
+
+    const buffer = await partBlob.arrayBuffer();
+    const digest = new Uint8Array(await crypto.subtle.digest("SHA-256", buffer));
+    partForm.querySelector("input[name='Checksum']").value = btoa(String.fromCharCode(...digest));
+}
+
+
+/**
+ * How much of a file is read at a time to checksum it.
+ */
+const CHECKSUM_CHUNK_SIZE = 4 * 1024 * 1024;
+
+/**
+ * Used to fill in the SHA-256 checksum of the whole file once it is picked, so
+ * the server can skip the upload if it already has the content. This is only
+ * done when the server deduplicates uploads, and if the upload starts before
+ * it finishes it just goes ahead as usual.
+ * 
+ * @param {HTMLInputElement} fileInput 
+ */
+async function checksumFile(fileInput) {
+    delete fileInput.dataset.checksum;
+    /** @type {File} */
+    const file = fileInput.files[0];
+    if (!("deduplicate" in fileInput.dataset) || !file) {
+        return;
+    }
+
+    // Read a chunk at a time, since files can be far larger than memory.
+    const hasher = new Sha256();
+    for (let offset = 0; offset < file.size; offset += CHECKSUM_CHUNK_SIZE) {
+        const chunk = await file.slice(offset, offset + CHECKSUM_CHUNK_SIZE).arrayBuffer();
+        if (fileInput.files[0] !== file) {
+            return;
+        }
+        hasher.update(new Uint8Array(chunk));
+    }
+    fileInput.dataset.checksum = btoa(String.fromCharCode(...hasher.digest()));
+}
+
+
+/**
+ * SHA-256 that can be fed a little at a time, unlike the one browsers have
+ * which needs everything in memory at once.
+ */
+class Sha256 {
+    static K = new Uint32Array([
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ]);
+
+    constructor() {
+        this.state = new Uint32Array([
+            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+        ]);
+        this.words = new Uint32Array(64);
+        this.block = new Uint8Array(64);
+        this.buffered = 0;
+        this.length = 0;
+    }
+
+    /**
+     * @param {Uint8Array} bytes 
+     */
+    update(bytes) {
+        this.length += bytes.length;
+        let offset = 0;
+        if (this.buffered > 0) {
+            offset = Math.min(64 - this.buffered, bytes.length);
+            this.block.set(bytes.subarray(0, offset), this.buffered);
+            this.buffered += offset;
+            if (this.buffered < 64) {
+                return;
+            }
+            this.compress(this.block, 0);
+            this.buffered = 0;
+        }
+        for (; offset + 64 <= bytes.length; offset += 64) {
+            this.compress(bytes, offset);
+        }
+        this.block.set(bytes.subarray(offset));
+        this.buffered = bytes.length - offset;
+    }
+
+    /**
+     * @returns {Uint8Array} The digest of everything that was fed in.
+     */
+    digest() {
+        const bits = this.length * 8;
+        const padding = new Uint8Array((this.buffered < 56 ? 56 : 120) - this.buffered + 8);
+        padding[0] = 0x80;
+        const view = new DataView(padding.buffer);
+        view.setUint32(padding.length - 8, Math.floor(bits / 0x100000000));
+        view.setUint32(padding.length - 4, bits >>> 0);
+        this.update(padding);
+
+        const digest = new Uint8Array(32);
+        const digestView = new DataView(digest.buffer);
+        this.state.forEach((word, index) => digestView.setUint32(index * 4, word));
+        return digest;
+    }
+
+    /**
+     * @param {Uint8Array} bytes 
+     * @param {number} offset Where the 64 byte block starts in `bytes`.
+     */
+    compress(bytes, offset) {
+        const rotate = (word, bits) => (word >>> bits) | (word << (32 - bits));
+        const words = this.words;
+        for (let i = 0; i < 16; i++) {
+            const start = offset + i * 4;
+            words[i] = (bytes[start] << 24) | (bytes[start + 1] << 16) | (bytes[start + 2] << 8) | bytes[start + 3];
+        }
+        for (let i = 16; i < 64; i++) {
+            const s0 = rotate(words[i - 15], 7) ^ rotate(words[i - 15], 18) ^ (words[i - 15] >>> 3);
+            const s1 = rotate(words[i - 2], 17) ^ rotate(words[i - 2], 19) ^ (words[i - 2] >>> 10);
+            words[i] = words[i - 16] + s0 + words[i - 7] + s1;
+        }
+
+        let [a, b, c, d, e, f, g, h] = this.state;
+        for (let i = 0; i < 64; i++) {
+            const s1 = rotate(e, 6) ^ rotate(e, 11) ^ rotate(e, 25);
+            const choice = (e & f) ^ (~e & g);
+            const temp1 = (h + s1 + choice + Sha256.K[i] + words[i]) | 0;
+            const s0 = rotate(a, 2) ^ rotate(a, 13) ^ rotate(a, 22);
+            const majority = (a & b) ^ (a & c) ^ (b & c);
+            const temp2 = (s0 + majority) | 0;
+            [h, g, f, e, d, c, b, a] = [g, f, e, (d + temp1) | 0, c, b, a, (temp1 + temp2) | 0];
+        }
+        [a, b, c, d, e, f, g, h].forEach((word, index) => this.state[index] += word);
+    }
+}
+
+
+/**
+ * Encrypts a single part with AES-GCM, bound to its position in the file so
+ * parts can't be swapped around.
+ * 
+ * @param {Blob} partBlob 
+ * @param {number} part 
+ * @returns {Promise<Blob>} The IV followed by the ciphertext.
+ */
+async function encryptPart(partBlob, part) {
+    const key = await crypto.subtle.importKey("raw", endToEndKey, "AES-GCM", false, ["encrypt"]);
+    const iv = crypto.getRandomValues(new Uint8Array(12));
+    const ciphertext = await crypto.subtle.encrypt(
+        { name: "AES-GCM", iv, additionalData: new TextEncoder().encode(String(part)) },
+        key,
+        await partBlob.arrayBuffer(),
+    );
+    return new Blob([iv, ciphertext]);
+}
+
+
+/**
+ * Reverses `encryptPart`.
+ * 
+ * @param {CryptoKey} key 
+ * @param {ArrayBuffer} encryptedPart 
+ * @param {number} part 
+ * @returns {Promise<ArrayBuffer>} The decrypted part.
+ */
+async function decryptPart(key, encryptedPart, part) {
+    return await crypto.subtle.decrypt(
+        {
+            name: "AES-GCM",
+            iv: encryptedPart.slice(0, 12),
+            additionalData: new TextEncoder().encode(String(part)),
+        },
+        key,
+        encryptedPart.slice(12),
+    );
+}
+
+
+/**
+ * Used to download an end-to-end encrypted file and decrypt it with the key
+ * in the URL fragment, which never makes its way to the server.
+ * 
+ * @param {string} fileSource Where to download the encrypted file from.
+ * @param {string} fileName What to save the decrypted file as.
+ * @param {number[]} partSizes The size of each encrypted part, in order.
+ */
+async function downloadEndToEndEncrypted(fileSource, fileName, partSizes) {
+    const error = document.getElementById("decrypt-error");
+    const progress = document.getElementById("decrypt-progress");
+    error.innerText = "";
+
+    const rawKey = new URLSearchParams(window.location.hash.slice(1)).get("key");
+    if (!rawKey) {
+        error.innerText = "The link is missing the key to decrypt this file.";
+        return;
+    }
+    if (!window.crypto?.subtle) {
+        error.innerText = "Decrypting isn't available in this browser, or over an insecure connection.";
+        return;
+    }
+
+    try {
+        const key = await crypto.subtle.importKey("raw", decodeBase64Url(rawKey), "AES-GCM", false, ["decrypt"]);
+
+        progress.hidden = false;
+        // Fetched whole, since every request for a file with a download limit
+        // counts as a download.
+        const response = await fetch(fileSource);
+        if (!response.ok) {
+            throw new Error(await response.text());
+        }
+        const encryptedFile = await response.arrayBuffer();
+
+        const decryptedParts = [];
+        let offset = 0;
+        for (const [part, size] of partSizes.entries()) {
+            decryptedParts.push(await decryptPart(key, encryptedFile.slice(offset, offset + size), part));
+            offset += size;
+            progress.value = ((part + 1) / partSizes.length) * 100;
+        }
+
+        const link = document.createElement("a");
+        link.href = URL.createObjectURL(new Blob(decryptedParts));
+        link.download = fileName;
+        link.click();
+        URL.revokeObjectURL(link.href);
+    } catch (err) {
+        console.error(err);
+        error.innerText = "Unable to decrypt this file, make sure you have the whole link you were given.";
+    } finally {
+        progress.hidden = true;
+    }
+}
+
+
+/**
+ * @param {Uint8Array} bytes 
+ * @returns {string} The bytes as unpadded base64url.
+ */
+function encodeBase64Url(bytes) {
+    return btoa(String.fromCharCode(...bytes)).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+
+/**
+ * @param {string} string Unpadded base64url.
+ * @returns {Uint8Array} The decoded bytes.
+ */
+function decodeBase64Url(string) {
+    const base64 = string.replace(/-/g, "+").replace(/_/g, "/");
+    return Uint8Array.from(atob(base64), (char) => char.charCodeAt(0));
+}
+
+
+/**
+ * Used to finalize a multipart upload once every part has been uploaded, and
+ * then go view the file.
+ * 
+ * @param {string} fileName 
+ * @param {string} token The upload session token.
+ * @param {string} viewUrl Where to view the file, which includes its key if it is encrypted.
+ */
+async function finalizeUpload(fileName, token, viewUrl) {
+    const response = await fetch(`/file/${fileName}/finalize`, {
+        method: "POST",
+        body: new URLSearchParams({ Token: token }),
+    });
+    if (response.ok) {
+        // The key to an end-to-end encrypted file only ever goes in the fragment.
+        window.location.href = endToEndKey ? `${viewUrl}#key=${encodeBase64Url(endToEndKey)}` : viewUrl;
+    } else {
+        document.getElementById("error-for-finalize").innerHTML = await response.text();
+    }
 }
 
 
@@ -80,4 +485,47 @@ function cloneNode(nodeId, newId, hidden=true) {
     newNode.id = newId;
     newNode.hidden = hidden;
     return newNode;
-}
\ No newline at end of file
+}
+
+
+/**
+ * Used to highlight the line or range of lines of a text file that the URL
+ * fragment points at, such as `#L10` or `#L10-L20`, and scroll to the first.
+ */
+function highlightLines() {
+    for (const line of document.querySelectorAll(".code-viewer .line.selected")) {
+        line.classList.remove("selected");
+    }
+
+    const match = window.location.hash.match(/^#L(\d+)(?:-L(\d+))?$/);
+    if (!match) {
+        return;
+    }
+    let start = Number(match[1]);
+    let end = Number(match[2] ?? match[1]);
+    if (end < start) {
+        [start, end] = [end, start];
+    }
+    for (let line = start; line <= end; line++) {
+        document.getElementById(`L${line}`)?.classList.add("selected");
+    }
+    document.getElementById(`L${start}`)?.scrollIntoView({ block: "center" });
+}
+
+
+/**
+ * Used to pick a range of lines by shift clicking the number of the line it
+ * should go to from the one that is already picked.
+ * 
+ * @param {MouseEvent} event 
+ */
+function selectLines(event) {
+    const lineNumber = event.target.closest(".line-number");
+    const match = window.location.hash.match(/^#L(\d+)/);
+    if (!lineNumber || !event.shiftKey || !match) {
+        return;
+    }
+
+    event.preventDefault();
+    window.location.hash = `L${match[1]}-L${lineNumber.dataset.line}`;
+}
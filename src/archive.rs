@@ -0,0 +1,536 @@
+//! Whole shares can be downloaded as a single ZIP archive, which is built as it
+//! is streamed out so it never has to be stored anywhere.
+//!
+//! Files are stored in it as they are rather than compressed, so the size of
+//! the archive is known up front. Their checksums aren't until they have been
+//! streamed through though, so those come after each of them in a data
+//! descriptor. Anything too large for a plain ZIP is written as ZIP64.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use axum::body::Bytes;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use opendal::Operator;
+
+use crate::{
+    encryption::ShareKey,
+    parts::{read_parts_range, FilePart},
+};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+/// ZIP 2.0, or 4.5 once ZIP64 is needed.
+const VERSION: u16 = 20;
+const ZIP64_VERSION: u16 = 45;
+/// The checksum and sizes are in the data descriptor, and the name is UTF-8.
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+const METHOD_STORED: u16 = 0;
+
+/// Fields that don't fit are set to this and moved into the ZIP64 fields.
+const ZIP64_MARKER: u32 = u32::MAX;
+
+/// A file to put in an archive.
+pub struct ArchiveEntry {
+    /// Path of the file within the archive.
+    pub name: String,
+    pub parts: Vec<FilePart>,
+    pub key: Option<ShareKey>,
+}
+
+impl ArchiveEntry {
+    fn size(&self) -> u64 { self.parts.iter().map(|part| part.size).sum() }
+}
+
+/// Where an entry ended up in the archive, for the central directory.
+struct EntryLayout {
+    name: String,
+    size: u64,
+    offset: u64,
+    zip64: bool,
+}
+
+impl EntryLayout {
+    fn new(name: String, size: u64, offset: u64) -> Self {
+        let zip64 = size >= ZIP64_MARKER as u64 || offset >= ZIP64_MARKER as u64;
+        Self {
+            name,
+            size,
+            offset,
+            zip64,
+        }
+    }
+
+    fn version(&self) -> u16 {
+        if self.zip64 {
+            ZIP64_VERSION
+        } else {
+            VERSION
+        }
+    }
+
+    fn local_file_header(&self, modified: DosDateTime) -> Vec<u8> {
+        let mut header = Vec::with_capacity(30 + self.name.len() + 20);
+        put_u32(&mut header, LOCAL_FILE_HEADER_SIGNATURE);
+        put_u16(&mut header, self.version());
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, modified.time);
+        put_u16(&mut header, modified.date);
+        // The checksum and sizes come in the data descriptor.
+        put_u32(&mut header, 0);
+        let size_marker = if self.zip64 { ZIP64_MARKER } else { 0 };
+        put_u32(&mut header, size_marker);
+        put_u32(&mut header, size_marker);
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(&mut header, if self.zip64 { 20 } else { 0 });
+        header.extend_from_slice(self.name.as_bytes());
+        if self.zip64 {
+            put_u16(&mut header, ZIP64_EXTRA_FIELD_ID);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, 0);
+            put_u64(&mut header, 0);
+        }
+        header
+    }
+
+    fn data_descriptor(&self, crc: u32) -> Vec<u8> {
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, crc);
+        if self.zip64 {
+            put_u64(&mut descriptor, self.size);
+            put_u64(&mut descriptor, self.size);
+        } else {
+            put_u32(&mut descriptor, self.size as u32);
+            put_u32(&mut descriptor, self.size as u32);
+        }
+        descriptor
+    }
+
+    fn central_directory_header(&self, crc: u32, modified: DosDateTime) -> Vec<u8> {
+        let mut header = Vec::with_capacity(46 + self.name.len() + 28);
+        put_u32(&mut header, CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+        put_u16(&mut header, ZIP64_VERSION);
+        put_u16(&mut header, self.version());
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, modified.time);
+        put_u16(&mut header, modified.date);
+        put_u32(&mut header, crc);
+        if self.zip64 {
+            put_u32(&mut header, ZIP64_MARKER);
+            put_u32(&mut header, ZIP64_MARKER);
+        } else {
+            put_u32(&mut header, self.size as u32);
+            put_u32(&mut header, self.size as u32);
+        }
+        put_u16(&mut header, self.name.len() as u16);
+        put_u16(&mut header, if self.zip64 { 28 } else { 0 });
+        // No comment, all on the first disk and no attributes.
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u32(
+            &mut header,
+            if self.zip64 {
+                ZIP64_MARKER
+            } else {
+                self.offset as u32
+            },
+        );
+        header.extend_from_slice(self.name.as_bytes());
+        if self.zip64 {
+            put_u16(&mut header, ZIP64_EXTRA_FIELD_ID);
+            put_u16(&mut header, 24);
+            put_u64(&mut header, self.size);
+            put_u64(&mut header, self.size);
+            put_u64(&mut header, self.offset);
+        }
+        header
+    }
+}
+
+/// The end of an archive with `entries`, whose central directory is
+/// `central_directory_size` bytes long and starts `central_directory_offset`
+/// bytes in.
+fn end_of_central_directory(
+    entries: &[EntryLayout],
+    central_directory_offset: u64,
+    central_directory_size: u64,
+) -> Vec<u8> {
+    let zip64 = entries.iter().any(|entry| entry.zip64)
+        || entries.len() >= u16::MAX as usize
+        || central_directory_offset >= ZIP64_MARKER as u64
+        || central_directory_size >= ZIP64_MARKER as u64;
+
+    let mut end = Vec::with_capacity(56 + 20 + 22);
+    if zip64 {
+        let zip64_end_offset = central_directory_offset + central_directory_size;
+        put_u32(&mut end, ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        // Size of the rest of the record.
+        put_u64(&mut end, 44);
+        put_u16(&mut end, ZIP64_VERSION);
+        put_u16(&mut end, ZIP64_VERSION);
+        put_u32(&mut end, 0);
+        put_u32(&mut end, 0);
+        put_u64(&mut end, entries.len() as u64);
+        put_u64(&mut end, entries.len() as u64);
+        put_u64(&mut end, central_directory_size);
+        put_u64(&mut end, central_directory_offset);
+
+        put_u32(&mut end, ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE);
+        put_u32(&mut end, 0);
+        put_u64(&mut end, zip64_end_offset);
+        put_u32(&mut end, 1);
+    }
+
+    put_u32(&mut end, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+    put_u16(&mut end, 0);
+    put_u16(&mut end, 0);
+    if zip64 {
+        put_u16(&mut end, u16::MAX);
+        put_u16(&mut end, u16::MAX);
+        put_u32(&mut end, ZIP64_MARKER);
+        put_u32(&mut end, ZIP64_MARKER);
+    } else {
+        put_u16(&mut end, entries.len() as u16);
+        put_u16(&mut end, entries.len() as u16);
+        put_u32(&mut end, central_directory_size as u32);
+        put_u32(&mut end, central_directory_offset as u32);
+    }
+    // No comment.
+    put_u16(&mut end, 0);
+    end
+}
+
+/// A ZIP archive of `entries`, along with how large it is.
+pub fn archive(
+    entries: Vec<ArchiveEntry>,
+    storage: &Operator,
+) -> (u64, impl Stream<Item = io::Result<Bytes>>) {
+    let modified = DosDateTime::from(Utc::now());
+
+    let mut offset = 0;
+    let mut layouts = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        let layout = EntryLayout::new(entry.name.clone(), entry.size(), offset);
+        offset += (layout.local_file_header(modified).len() + layout.data_descriptor(0).len())
+            as u64
+            + layout.size;
+        layouts.push(layout);
+    }
+    let central_directory_offset = offset;
+    let central_directory_size = layouts
+        .iter()
+        .map(|layout| layout.central_directory_header(0, modified).len() as u64)
+        .sum::<u64>();
+    let size = central_directory_offset
+        + central_directory_size
+        + end_of_central_directory(&layouts, central_directory_offset, central_directory_size).len()
+            as u64;
+
+    // Filled in as each entry is streamed through, for the central directory.
+    let crcs = Arc::new(Mutex::new(Vec::with_capacity(entries.len())));
+    let layouts = Arc::new(layouts);
+
+    let storage = storage.clone();
+    let body = stream::iter(entries.into_iter().enumerate())
+        .map({
+            let crcs = crcs.clone();
+            let layouts = layouts.clone();
+            move |(index, entry)| {
+                let layout = &layouts[index];
+                let local_file_header = layout.local_file_header(modified);
+                let hasher = Arc::new(Mutex::new((crc32fast::Hasher::new(), 0u64)));
+                let contents =
+                    read_parts_range(&entry.parts, 0..layout.size, entry.key.as_ref(), &storage)
+                        .inspect_ok({
+                            let hasher = hasher.clone();
+                            move |bytes| {
+                                let (crc, size) = &mut *hasher.lock().expect("crc is not poisoned");
+                                crc.update(bytes);
+                                *size += bytes.len() as u64;
+                            }
+                        });
+                let data_descriptor = {
+                    let crcs = crcs.clone();
+                    let layouts = layouts.clone();
+                    async move {
+                        let layout = &layouts[index];
+                        let (crc, size) = hasher.lock().expect("crc is not poisoned").clone();
+                        // Anything else would throw off where everything after it is.
+                        if size != layout.size {
+                            return Err(io::Error::other(format!(
+                                "'{}' is {size} bytes instead of {}",
+                                layout.name, layout.size
+                            )));
+                        }
+                        let crc = crc.finalize();
+                        crcs.lock().expect("crcs are not poisoned").push(crc);
+                        Ok(Bytes::from(layout.data_descriptor(crc)))
+                    }
+                };
+
+                stream::once(async move { Ok(Bytes::from(local_file_header)) })
+                    .chain(contents)
+                    .chain(stream::once(data_descriptor))
+            }
+        })
+        .flatten()
+        .chain(stream::once(async move {
+            let crcs = crcs.lock().expect("crcs are not poisoned");
+            let mut central_directory = Vec::new();
+            for (layout, crc) in layouts.iter().zip(crcs.iter()) {
+                central_directory.extend(layout.central_directory_header(*crc, modified));
+            }
+            central_directory.extend(end_of_central_directory(
+                &layouts,
+                central_directory_offset,
+                central_directory_size,
+            ));
+            Ok(Bytes::from(central_directory))
+        }));
+
+    (size, body)
+}
+
+/// The MS-DOS date and time ZIP archives store when files were last modified in.
+#[derive(Clone, Copy)]
+struct DosDateTime {
+    time: u16,
+    date: u16,
+}
+
+impl From<DateTime<Utc>> for DosDateTime {
+    fn from(datetime: DateTime<Utc>) -> Self {
+        Self {
+            // Seconds are only stored to the nearest two.
+            time: ((datetime.hour() << 11) | (datetime.minute() << 5) | (datetime.second() / 2))
+                as u16,
+            // Years are counted from 1980.
+            date: (((datetime.year().clamp(1980, 2107) as u32 - 1980) << 9)
+                | (datetime.month() << 5)
+                | datetime.day()) as u16,
+        }
+    }
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) { buffer.extend_from_slice(&value.to_le_bytes()); }
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) { buffer.extend_from_slice(&value.to_le_bytes()); }
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) { buffer.extend_from_slice(&value.to_le_bytes()); }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use opendal::services;
+
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Stores `contents` as a file split up into parts of `part_size`.
+    async fn entry(
+        name: &str,
+        contents: &[u8],
+        part_size: usize,
+        storage: &Operator,
+    ) -> ArchiveEntry {
+        let mut parts = Vec::new();
+        for (index, chunk) in contents.chunks(part_size).enumerate() {
+            let path = format!("{name}/{index}");
+            storage.write(&path, chunk.to_vec()).await.unwrap();
+            parts.push(FilePart {
+                path,
+                size: chunk.len() as u64,
+                codec: None,
+                encrypted: false,
+                stored_size: chunk.len() as u64,
+            });
+        }
+        ArchiveEntry {
+            name: name.to_string(),
+            parts,
+            key: None,
+        }
+    }
+
+    async fn collect(body: impl Stream<Item = io::Result<Bytes>>) -> io::Result<Vec<u8>> {
+        body.try_fold(Vec::new(), |mut bytes, chunk| async move {
+            bytes.extend_from_slice(&chunk);
+            Ok(bytes)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn files_are_stored_with_their_checksums() {
+        let storage = Operator::new(services::Memory::default()).unwrap().finish();
+        let files: [(&str, &[u8]); 3] = [
+            ("hello.txt", b"Hello, world!"),
+            ("folder/empty", b""),
+            ("folder/naïve.bin", &[7; 100]),
+        ];
+        let mut entries = Vec::new();
+        for (name, contents) in files {
+            entries.push(entry(name, contents, 30, &storage).await);
+        }
+
+        let (size, body) = archive(entries, &storage);
+        let archive = collect(body).await.unwrap();
+        assert_eq!(archive.len() as u64, size);
+
+        // Each file is a local file header, its contents and a data descriptor.
+        let mut offset = 0;
+        let mut offsets = Vec::new();
+        for (name, contents) in files {
+            offsets.push(offset);
+            assert_eq!(u32_at(&archive, offset), LOCAL_FILE_HEADER_SIGNATURE);
+            assert_eq!(u16_at(&archive, offset + 6), FLAGS);
+            assert_eq!(u16_at(&archive, offset + 8), METHOD_STORED);
+            let name_length = u16_at(&archive, offset + 26) as usize;
+            assert_eq!(u16_at(&archive, offset + 28), 0);
+            offset += 30;
+            assert_eq!(&archive[offset..offset + name_length], name.as_bytes());
+            offset += name_length;
+            assert_eq!(&archive[offset..offset + contents.len()], contents);
+            offset += contents.len();
+            assert_eq!(u32_at(&archive, offset), DATA_DESCRIPTOR_SIGNATURE);
+            assert_eq!(u32_at(&archive, offset + 4), crc32fast::hash(contents));
+            assert_eq!(u32_at(&archive, offset + 8), contents.len() as u32);
+            assert_eq!(u32_at(&archive, offset + 12), contents.len() as u32);
+            offset += 16;
+        }
+
+        // Followed by the central directory, which points back at each of them.
+        let central_directory_offset = offset;
+        for ((name, contents), local_offset) in files.into_iter().zip(offsets) {
+            assert_eq!(u32_at(&archive, offset), CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+            assert_eq!(u32_at(&archive, offset + 16), crc32fast::hash(contents));
+            assert_eq!(u32_at(&archive, offset + 20), contents.len() as u32);
+            assert_eq!(u32_at(&archive, offset + 24), contents.len() as u32);
+            let name_length = u16_at(&archive, offset + 28) as usize;
+            assert_eq!(u32_at(&archive, offset + 42), local_offset as u32);
+            offset += 46;
+            assert_eq!(&archive[offset..offset + name_length], name.as_bytes());
+            offset += name_length;
+        }
+
+        assert_eq!(u32_at(&archive, offset), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&archive, offset + 8), 3);
+        assert_eq!(u16_at(&archive, offset + 10), 3);
+        assert_eq!(
+            u32_at(&archive, offset + 12) as usize,
+            offset - central_directory_offset
+        );
+        assert_eq!(
+            u32_at(&archive, offset + 16) as usize,
+            central_directory_offset
+        );
+        assert_eq!(offset + 22, archive.len());
+    }
+
+    #[tokio::test]
+    async fn an_empty_archive_is_just_the_end() {
+        let storage = Operator::new(services::Memory::default()).unwrap().finish();
+
+        let (size, body) = archive(Vec::new(), &storage);
+        let archive = collect(body).await.unwrap();
+        assert_eq!(size, 22);
+        assert_eq!(u32_at(&archive, 0), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+    }
+
+    #[tokio::test]
+    async fn files_that_changed_size_fail_the_archive() {
+        let storage = Operator::new(services::Memory::default()).unwrap().finish();
+        let mut entry = entry("hello.txt", b"Hello, world!", 30, &storage).await;
+        entry.parts[0].size += 1;
+
+        let (_, body) = archive(vec![entry], &storage);
+        assert!(collect(body).await.is_err());
+    }
+
+    #[test]
+    fn large_files_are_written_as_zip64() {
+        let modified = DosDateTime::from(Utc::now());
+        let small = EntryLayout::new("small".to_string(), 10, 0);
+        let large = EntryLayout::new("large".to_string(), 5 << 30, 100);
+        let far = EntryLayout::new("far".to_string(), 10, 5 << 30);
+        assert!(!small.zip64);
+        assert!(large.zip64);
+        assert!(far.zip64);
+
+        let header = large.local_file_header(modified);
+        assert_eq!(u16_at(&header, 4), ZIP64_VERSION);
+        assert_eq!(u32_at(&header, 18), ZIP64_MARKER);
+        assert_eq!(u32_at(&header, 22), ZIP64_MARKER);
+        assert_eq!(u16_at(&header, 28), 20);
+        assert_eq!(u16_at(&header, 30 + 5), ZIP64_EXTRA_FIELD_ID);
+
+        let descriptor = large.data_descriptor(0);
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(u64_at(&descriptor, 8), 5 << 30);
+        assert_eq!(u64_at(&descriptor, 16), 5 << 30);
+
+        let header = far.central_directory_header(0, modified);
+        assert_eq!(u32_at(&header, 20), ZIP64_MARKER);
+        assert_eq!(u32_at(&header, 42), ZIP64_MARKER);
+        let extra = 46 + 3;
+        assert_eq!(u16_at(&header, extra), ZIP64_EXTRA_FIELD_ID);
+        assert_eq!(u16_at(&header, extra + 2), 24);
+        assert_eq!(u64_at(&header, extra + 4), 10);
+        assert_eq!(u64_at(&header, extra + 12), 10);
+        assert_eq!(u64_at(&header, extra + 20), 5 << 30);
+    }
+
+    #[test]
+    fn the_end_of_a_large_archive_is_zip64() {
+        let plain = end_of_central_directory(&[EntryLayout::new("a".to_string(), 1, 0)], 40, 50);
+        assert_eq!(plain.len(), 22);
+
+        let entries = [EntryLayout::new("a".to_string(), 5 << 30, 0)];
+        let end = end_of_central_directory(&entries, (5 << 30) + 100, 50);
+        assert_eq!(end.len(), 56 + 20 + 22);
+        assert_eq!(u32_at(&end, 0), ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u64_at(&end, 32), 1);
+        assert_eq!(u64_at(&end, 40), 50);
+        assert_eq!(u64_at(&end, 48), (5 << 30) + 100);
+        assert_eq!(
+            u32_at(&end, 56),
+            ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE
+        );
+        // The locator points at the record, right after the central directory.
+        assert_eq!(u64_at(&end, 64), (5 << 30) + 150);
+        assert_eq!(u32_at(&end, 76), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u32_at(&end, 76 + 16), ZIP64_MARKER);
+    }
+
+    #[test]
+    fn modification_times_are_in_dos_format() {
+        let modified = DosDateTime::from(Utc.with_ymd_and_hms(2024, 7, 15, 13, 45, 31).unwrap());
+        assert_eq!(modified.time, (13 << 11) | (45 << 5) | 15);
+        assert_eq!(modified.date, (44 << 9) | (7 << 5) | 15);
+    }
+}
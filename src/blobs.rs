@@ -0,0 +1,263 @@
+use base64::{
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+    Engine,
+};
+use futures::TryStreamExt;
+use opendal::{Entry, Metakey, Operator};
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    manifest::{lock_manifest, Manifest, PartManifest},
+    parts::part_path,
+    util::{copy_file, get_expiration_for_directory, GetDirectoryExpirationError},
+};
+
+/// Top level directory content addressed blobs live in, next to the expiration
+/// directories.
+pub const BLOBS_DIRECTORY: &str = "blobs";
+
+const BLOB_MANIFEST_FILE_NAME: &str = "manifest.json";
+const REFERENCES_DIRECTORY: &str = "refs";
+
+/// The layout of a blob's parts, so shares can reference it without uploading.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlobManifest {
+    pub size: u64,
+    pub parts: Vec<PartManifest>,
+    /// Media type of the content, see [`crate::util::sniff_content_type`].
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BlobError {
+    #[error("Invalid checksum.")]
+    InvalidChecksum,
+    #[error("Blob not found.")]
+    NotFound,
+    #[error("Blob isn't the size it was expected to be.")]
+    SizeMismatch,
+    #[error("Unable to access blob: {0}")]
+    Storage(#[from] opendal::Error),
+    #[error("Invalid blob manifest: {0}")]
+    Invalid(#[from] serde_json::Error),
+}
+
+/// Key of the blob holding the content with the given (base64 encoded SHA-256)
+/// checksum, which is safe to use within a path.
+pub fn blob_key(checksum: &str) -> Option<String> {
+    let digest = BASE64_STANDARD.decode(checksum).ok()?;
+    Some(BASE64_URL_SAFE_NO_PAD.encode(digest))
+}
+
+pub fn blob_directory(key: &str) -> RelativePathBuf { RelativePath::new(BLOBS_DIRECTORY).join(key) }
+
+/// Marks that the share in `share_directory` references the blob, which keeps
+/// it alive until the share's expiration directory expires.
+fn reference_path(key: &str, share_directory: &RelativePath) -> RelativePathBuf {
+    blob_directory(key)
+        .join(REFERENCES_DIRECTORY)
+        .join(share_directory)
+}
+
+pub async fn read_blob_manifest(key: &str, storage: &Operator) -> Result<BlobManifest, BlobError> {
+    let path = blob_directory(key).join(BLOB_MANIFEST_FILE_NAME);
+    match storage.read(path.as_str()).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(err) if err.kind() == opendal::ErrorKind::NotFound => Err(BlobError::NotFound),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Moves the parts of a finalized share into the blob for its content, unless
+/// that blob already exists in which case the share's parts are just dropped.
+///
+/// Either way the share ends up referencing the blob, and the blob's key and
+/// layout (which can differ from how the share was split up) are returned.
+pub async fn store_blob(
+    share_directory: &RelativePath,
+    manifest: &Manifest,
+    checksum: &str,
+    storage: &Operator,
+) -> Result<(String, BlobManifest), BlobError> {
+    let key = blob_key(checksum).ok_or(BlobError::InvalidChecksum)?;
+    let directory = blob_directory(&key);
+    let _guard = lock_manifest(&directory).await;
+
+    storage
+        .write(reference_path(&key, share_directory).as_str(), Vec::new())
+        .await?;
+
+    let parts = manifest.parts.iter().flatten().cloned().collect::<Vec<_>>();
+    let blob_manifest = match read_blob_manifest(&key, storage).await {
+        Ok(blob_manifest) => {
+            tracing::info!("Deduplicated {share_directory} into existing blob {key}");
+            blob_manifest
+        }
+        Err(BlobError::NotFound) => {
+            for part in 0..parts.len() {
+                copy_file(
+                    &part_path(share_directory, part),
+                    &part_path(&directory, part),
+                    storage,
+                )
+                .await?;
+            }
+
+            let blob_manifest = BlobManifest {
+                size: parts.iter().map(|part| part.size).sum(),
+                parts: parts.clone(),
+                content_type: manifest.content_type.clone(),
+            };
+            storage
+                .write_with(
+                    directory.join(BLOB_MANIFEST_FILE_NAME).as_str(),
+                    serde_json::to_vec(&blob_manifest)?,
+                )
+                .content_type("application/json")
+                .await?;
+            blob_manifest
+        }
+        Err(err) => return Err(err),
+    };
+
+    for part in 0..parts.len() {
+        storage
+            .delete(part_path(share_directory, part).as_str())
+            .await?;
+    }
+
+    Ok((key, blob_manifest))
+}
+
+/// Makes the share in `share_directory` reference an existing blob of `size`
+/// bytes, returning the blob's layout so a manifest can be created for the
+/// share. Nothing is referenced if the blob is any other size.
+pub async fn reference_blob(
+    key: &str,
+    size: u64,
+    share_directory: &RelativePath,
+    storage: &Operator,
+) -> Result<BlobManifest, BlobError> {
+    let _guard = lock_manifest(&blob_directory(key)).await;
+
+    let blob_manifest = read_blob_manifest(key, storage).await?;
+    if blob_manifest.size != size {
+        return Err(BlobError::SizeMismatch);
+    }
+    storage
+        .write(reference_path(key, share_directory).as_str(), Vec::new())
+        .await?;
+
+    Ok(blob_manifest)
+}
+
+/// Drops the reference the share in `share_directory` holds on the blob, and
+/// the blob itself if that was the last one.
+pub async fn dereference_blob(
+    key: &str,
+    share_directory: &RelativePath,
+    storage: &Operator,
+) -> Result<(), BlobError> {
+    let directory = blob_directory(key);
+    let _guard = lock_manifest(&directory).await;
+
+    storage
+        .delete(reference_path(key, share_directory).as_str())
+        .await?;
+
+    let has_references = storage
+        .list_with(&format!("{}/", directory.join(REFERENCES_DIRECTORY)))
+        .recursive(true)
+        .metakey(Metakey::Mode)
+        .await?
+        .iter()
+        .any(|entry| entry.metadata().is_file());
+    if !has_references {
+        storage.remove_all(&format!("{directory}/")).await?;
+
+        tracing::info!("Removed unreferenced blob '{directory}'");
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(thiserror::Error, Debug)]
+pub enum CleanupBlobError {
+    #[error("Unable to list blob references for '{0}': {1}")]
+    UnableToListReferences(RelativePathBuf, opendal::Error),
+    #[error("Unable to parse reference expiration for '{0}': {1}")]
+    UnableToParseReferenceExpiration(RelativePathBuf, GetDirectoryExpirationError),
+    #[error("Unable to remove '{0}': {1}")]
+    UnableToRemove(RelativePathBuf, opendal::Error),
+}
+
+/// Drops every expired reference to the blob in `directory`, and the blob itself
+/// once nothing references it anymore.
+pub async fn cleanup_blob(directory: Entry, storage: &Operator) -> Result<(), CleanupBlobError> {
+    let directory = RelativePath::new(directory.path().trim_end_matches('/'));
+    let _guard = lock_manifest(directory).await;
+
+    let references_directory = directory.join(REFERENCES_DIRECTORY);
+    let references = storage
+        .list_with(&format!("{references_directory}/"))
+        .recursive(true)
+        .metakey(Metakey::Mode)
+        .await
+        .map_err(|err| CleanupBlobError::UnableToListReferences(directory.into(), err))?;
+
+    let mut live_references = 0;
+    for reference in references.iter().filter(|entry| entry.metadata().is_file()) {
+        let path = RelativePath::new(reference.path());
+        let expiration_directory = path
+            .strip_prefix(&references_directory)
+            .ok()
+            .and_then(|path| path.iter().next())
+            .unwrap_or_default();
+        let expiration = get_expiration_for_directory(RelativePath::new(expiration_directory))
+            .map_err(|err| CleanupBlobError::UnableToParseReferenceExpiration(path.into(), err))?;
+
+        if chrono::Utc::now() >= expiration {
+            storage
+                .delete(path.as_str())
+                .await
+                .map_err(|err| CleanupBlobError::UnableToRemove(path.into(), err))?;
+        } else {
+            live_references += 1;
+        }
+    }
+
+    if live_references == 0 {
+        storage
+            .remove_all(&format!("{directory}/"))
+            .await
+            .map_err(|err| CleanupBlobError::UnableToRemove(directory.into(), err))?;
+
+        tracing::info!("Removed unreferenced blob '{directory}'");
+    }
+
+    Ok(())
+}
+
+/// Cleans up every blob, see [`cleanup_blob`].
+pub async fn cleanup_blobs(storage: &Operator) -> Result<(), opendal::Error> {
+    storage
+        .lister(&format!("{BLOBS_DIRECTORY}/"))
+        .await?
+        .try_for_each(|entry| {
+            let storage = storage.clone();
+            async move {
+                // Some services list the directory itself alongside its children.
+                let is_blobs_directory = entry.path().trim_end_matches('/') == BLOBS_DIRECTORY;
+                if entry.metadata().is_dir() && !is_blobs_directory {
+                    if let Err(err) = cleanup_blob(entry, &storage).await {
+                        tracing::error!("{err}");
+                    }
+                }
+                Ok(())
+            }
+        })
+        .await
+}
@@ -2,7 +2,11 @@ use futures::StreamExt;
 use opendal::{Entry, Operator};
 use relative_path::{Component, RelativePath, RelativePathBuf};
 
-use crate::util::{get_expiration_for_directory, GetDirectoryExpirationError};
+use crate::{
+    blobs::{cleanup_blobs, BLOBS_DIRECTORY},
+    redirects::{cleanup_redirects, REDIRECTS_DIRECTORY},
+    util::{get_expiration_for_directory, GetDirectoryExpirationError},
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum CleanupError {
@@ -49,11 +53,27 @@ pub enum CleanupEntryError {
     UnableToParseDirectoryExpiration(RelativePathBuf, GetDirectoryExpirationError),
     #[error("Unable to remove directory '{0}': {1}")]
     UnableToRemoveDirectory(RelativePathBuf, opendal::Error),
+    #[error("Unable to cleanup blobs: {0}")]
+    UnableToCleanupBlobs(opendal::Error),
+    #[error("Unable to cleanup redirects: {0}")]
+    UnableToCleanupRedirects(opendal::Error),
 }
 
 async fn cleanup_entry(entry: Entry, storage: Operator) -> Result<(), CleanupEntryError> {
     let path = entry.path();
-    if let Some(Component::Normal(directory)) = RelativePath::new(path).components().next() {
+    if let Some(Component::Normal(BLOBS_DIRECTORY)) = RelativePath::new(path).components().next() {
+        // Blobs outlive any one expiration directory, so they are tracked by reference instead.
+        cleanup_blobs(&storage)
+            .await
+            .map_err(CleanupEntryError::UnableToCleanupBlobs)
+    } else if let Some(Component::Normal(REDIRECTS_DIRECTORY)) =
+        RelativePath::new(path).components().next()
+    {
+        // Redirects outlive the expiration directory they come from, like blobs.
+        cleanup_redirects(&storage)
+            .await
+            .map_err(CleanupEntryError::UnableToCleanupRedirects)
+    } else if let Some(Component::Normal(directory)) = RelativePath::new(path).components().next() {
         let directory_expiration = get_expiration_for_directory(RelativePath::new(directory))
             .map_err(|err| {
                 CleanupEntryError::UnableToParseDirectoryExpiration(directory.into(), err)
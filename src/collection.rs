@@ -0,0 +1,81 @@
+//! Several files can be shared together as a collection, under a file name of
+//! its own. Each file in it is still a share in its own right, in the same
+//! expiration directory as the collection so they all expire together.
+//!
+//! When the files were uploaded in a folder, the collection also keeps where
+//! each of them was within it.
+
+use opendal::Operator;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::ManifestError;
+
+const COLLECTION_FILE_NAME: &str = "collection.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collection {
+    /// The shares in the collection, in the order they were uploaded.
+    pub files: Vec<CollectionFile>,
+    /// Digest of the token whoever shared the files can manage them with, see
+    /// [`crate::owner::OwnerToken`].
+    #[serde(default)]
+    pub owner_token_digest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CollectionFile {
+    /// File name the file is shared under, which expires along with the collection.
+    pub file_name: String,
+    /// Where the file is within the folder it was uploaded in, if it was, see
+    /// [`crate::util::sanitize_relative_path`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<RelativePathBuf>,
+}
+
+impl Collection {
+    /// Whether any of the files in the collection were uploaded in a folder.
+    pub fn is_folder(&self) -> bool { self.files.iter().any(|file| file.path.is_some()) }
+}
+
+/// Where the files of the collection in `collection_directory` are stored,
+/// which is the expiration directory it is in.
+pub fn files_directory(collection_directory: &RelativePath) -> &RelativePath {
+    collection_directory
+        .parent()
+        .unwrap_or(RelativePath::new(""))
+}
+
+fn collection_path(collection_directory: &RelativePath) -> RelativePathBuf {
+    collection_directory.join(COLLECTION_FILE_NAME)
+}
+
+/// The collection in `collection_directory`, if there is one.
+pub async fn read_collection(
+    collection_directory: &RelativePath,
+    storage: &Operator,
+) -> Result<Option<Collection>, ManifestError> {
+    match storage
+        .read(collection_path(collection_directory).as_str())
+        .await
+    {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == opendal::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn write_collection(
+    collection_directory: &RelativePath,
+    collection: &Collection,
+    storage: &Operator,
+) -> Result<(), ManifestError> {
+    storage
+        .write_with(
+            collection_path(collection_directory).as_str(),
+            serde_json::to_vec(collection)?,
+        )
+        .content_type("application/json")
+        .await?;
+    Ok(())
+}
@@ -1,2 +1,3 @@
 pub mod error_page;
 pub mod page;
+pub mod share_for;
@@ -25,6 +25,7 @@ pub fn page(content: Markup, is_index: bool) -> Markup {
                 title { "SimpleSharingShouldNotBeThisHard.com" }
 
                 link rel="stylesheet" type="text/css" href=(css_source);
+                link rel="stylesheet" type="text/css" href="/public/highlight.css";
                 link rel="apple-touch-icon" sizes="180x180" href="/public/apple-touch-icon.png";
                 link rel="icon" type="image/png" sizes="32x32" href="/public/favicon-32x32.png";
                 link rel="icon" type="image/png" sizes="16x16" href="/public/favicon-16x16.png";
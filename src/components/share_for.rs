@@ -0,0 +1,37 @@
+use maud::{html, Markup};
+
+use crate::config::{format_duration, Config};
+
+/// Picks how long to share a file for, out of the configured options or
+/// anything custom within the allowed range.
+pub fn share_for_field(config: &Config) -> Markup {
+    html! {
+        label for="share-for" { "Share for: " }
+        select id="share-for" name="Share for"
+        _="on change
+            if #custom-share-for.selected
+                remove @hidden from #custom-expiration
+            else
+                add @hidden to #custom-expiration
+            end" {
+            @for share_for_option in &config.share_for_options {
+                option { (share_for_option.label) }
+            }
+            // Whatever is entered below ends up as the value of this option.
+            option id="custom-share-for" value="" { "Custom..." }
+        }
+        span id="custom-expiration" hidden {
+            br;br;
+            input type="text" aria-label="Custom duration" placeholder="2h 30m"
+            _="on input set #custom-share-for.value to my value";
+            " or until "
+            input type="datetime-local" aria-label="Custom expiration"
+            _="on change set #custom-share-for.value to toISOString(my value)";
+            br;
+            sub {
+                "Anywhere from " (format_duration(config.min_share_for))
+                " to " (format_duration(config.max_share_for)) "."
+            }
+        }
+    }
+}
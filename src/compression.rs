@@ -0,0 +1,90 @@
+use std::io;
+
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use axum::{body::Bytes, http::HeaderMap};
+use futures::{Stream, TryStream, TryStreamExt};
+use mime_guess::mime;
+use relative_path::RelativePath;
+use serde::{Deserialize, Serialize};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// How a part is compressed at rest.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+}
+
+impl Codec {
+    /// The matching `Content-Encoding`, so compressed parts can be passed
+    /// straight through to clients that understand them.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+        }
+    }
+}
+
+/// Whether a file is worth compressing, judging by its extension.
+///
+/// Most binary formats (images, video, archives) are already compressed, so
+/// only text and a few structured text formats are.
+pub fn is_compressible(file_name: &RelativePath) -> bool {
+    let mime = mime_guess::from_path(file_name.as_str()).first_or_octet_stream();
+    match (mime.type_(), mime.subtype()) {
+        (mime::TEXT, _) => true,
+        (mime::APPLICATION, subtype) => matches!(
+            subtype.as_str(),
+            "json" | "xml" | "javascript" | "x-ndjson" | "x-yaml" | "toml" | "sql"
+        ),
+        (mime::IMAGE, mime::SVG) => true,
+        _ => false,
+    }
+}
+
+/// Whether the client accepts `encoding`, according to its `Accept-Encoding`
+/// headers.
+pub fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(axum::http::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parameters = coding.split(';').map(str::trim);
+            let is_encoding = parameters
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case(encoding));
+            // A quality of zero means "anything but this".
+            let is_refused = parameters.any(|parameter| {
+                parameter
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    .is_some_and(|quality| quality == 0.0)
+            });
+            is_encoding && !is_refused
+        })
+}
+
+pub fn compress<S>(codec: Codec, body: S) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: TryStream<Ok = Bytes, Error = io::Error>,
+{
+    match codec {
+        Codec::Zstd => ReaderStream::new(ZstdEncoder::new(StreamReader::new(body.into_stream()))),
+    }
+}
+
+pub fn decompress<S>(codec: Codec, body: S) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: TryStream<Ok = Bytes, Error = io::Error>,
+{
+    match codec {
+        Codec::Zstd => {
+            let mut decoder = ZstdDecoder::new(StreamReader::new(body.into_stream()));
+            // Parts that were written in multiple frames are still a single part.
+            decoder.multiple_members(true);
+            ReaderStream::new(decoder)
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::{ops::RangeInclusive, path::Path, str::FromStr};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use figment::{
+    providers::{Env, Format, Serialized, Toml},
+    Figment,
+};
+use serde::{Deserialize, Serialize};
+
+/// Environment variables starting with this override what is in the config file,
+/// such as `SHARING_PART_SIZE=10000000`.
+const ENV_PREFIX: &str = "SHARING_";
+
+/// Everything about how the service behaves that can be tuned per deployment,
+/// loaded from a TOML file and the environment at startup.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// How long files can be shared for, in the order they are offered. The
+    /// first one is the default.
+    pub share_for_options: Vec<ShareForOption>,
+    /// The shortest a file can be shared for.
+    #[serde(with = "duration")]
+    pub min_share_for: TimeDelta,
+    /// The longest a file can be shared for, whether picked as a duration or
+    /// as the date and time it expires.
+    #[serde(with = "duration")]
+    pub max_share_for: TimeDelta,
+    /// Files that expire within the same span of this long are grouped in a
+    /// directory, which is cleaned up as a whole once they have all expired.
+    ///
+    /// Changing this makes files shared before the change unreachable, though
+    /// they are still cleaned up.
+    #[serde(with = "duration")]
+    pub bucket_duration: TimeDelta,
+    /// Cron schedule (with seconds) for cleaning up expired files.
+    pub cleanup_schedule: String,
+    /// How many bytes to buffer before writing them out to storage, which is
+    /// also how large each chunk written to it is. Storage with a minimum size
+    /// for the parts of a multipart upload (5 MiB for S3) may need this raised.
+    pub write_buffer_size: usize,
+    /// How large each part of a file that is uploaded in parts is.
+    pub part_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShareForOption {
+    pub label: String,
+    #[serde(with = "duration")]
+    pub duration: TimeDelta,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let share_for_options = [
+            ("30 minutes", TimeDelta::minutes(30)),
+            ("1 hour", TimeDelta::hours(1)),
+            ("6 hours", TimeDelta::hours(6)),
+            ("12 hours", TimeDelta::hours(12)),
+            ("1 day", TimeDelta::days(1)),
+            ("3 days", TimeDelta::days(3)),
+        ]
+        .into_iter()
+        .map(|(label, duration)| ShareForOption {
+            label: label.to_string(),
+            duration,
+        })
+        .collect();
+
+        let cleanup_schedule = if cfg!(debug_assertions) {
+            "0/30 * * * * *"
+        } else {
+            "0 0/30 * * * *"
+        };
+
+        Self {
+            share_for_options,
+            min_share_for: TimeDelta::minutes(1),
+            max_share_for: TimeDelta::days(3),
+            bucket_duration: TimeDelta::hours(1),
+            cleanup_schedule: cleanup_schedule.to_string(),
+            write_buffer_size: 625000,
+            part_size: 5000000,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Unable to load config: {0}")]
+    Load(#[from] Box<figment::Error>),
+    #[error("At least one share for option is required.")]
+    NoShareForOptions,
+    #[error("'{0}' has to be greater than zero.")]
+    NotPositive(&'static str),
+    #[error("'max_share_for' can't be less than 'min_share_for'.")]
+    InvalidShareForRange,
+    #[error("Share for option '{0}' is outside of 'min_share_for' and 'max_share_for'.")]
+    ShareForOptionOutOfRange(String),
+    #[error("Invalid cleanup schedule: {0}")]
+    InvalidCleanupSchedule(#[from] cron::error::Error),
+}
+
+impl Config {
+    /// Loads the config from the TOML file at `path` if there is one, with any
+    /// `SHARING_` environment variables on top, and the defaults for anything
+    /// neither sets.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let config: Self = Figment::from(Serialized::defaults(Self::default()))
+            .merge(Toml::file(path))
+            .merge(Env::prefixed(ENV_PREFIX))
+            .extract()
+            .map_err(Box::new)?;
+
+        if config.share_for_options.is_empty() {
+            return Err(ConfigError::NoShareForOptions);
+        }
+        if config.min_share_for <= TimeDelta::zero() {
+            return Err(ConfigError::NotPositive("min_share_for"));
+        }
+        if config.max_share_for < config.min_share_for {
+            return Err(ConfigError::InvalidShareForRange);
+        }
+        if let Some(option) = config
+            .share_for_options
+            .iter()
+            .find(|option| !config.share_for_range().contains(&option.duration))
+        {
+            return Err(ConfigError::ShareForOptionOutOfRange(option.label.clone()));
+        }
+        if config.bucket_duration <= TimeDelta::zero() {
+            return Err(ConfigError::NotPositive("bucket_duration"));
+        }
+        if config.write_buffer_size == 0 {
+            return Err(ConfigError::NotPositive("write_buffer_size"));
+        }
+        if config.part_size == 0 {
+            return Err(ConfigError::NotPositive("part_size"));
+        }
+        cron::Schedule::from_str(&config.cleanup_schedule)?;
+
+        Ok(config)
+    }
+
+    fn share_for_range(&self) -> RangeInclusive<TimeDelta> {
+        self.min_share_for..=self.max_share_for
+    }
+
+    /// When a file shared at `now` expires, given what was picked to share it
+    /// for. That is either the label of one of the options, any duration such
+    /// as `2h 30m`, or an RFC 3339 date and time such as `2024-08-01T12:00:00Z`.
+    pub fn expiration_for(
+        &self,
+        share_for: &str,
+        now: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>, ExpirationError> {
+        let share_for = share_for.trim();
+        if share_for.is_empty() {
+            return Err(ExpirationError::Missing);
+        }
+        let option = self
+            .share_for_options
+            .iter()
+            .find(|option| option.label == share_for);
+        let expiration = if let Some(option) = option {
+            now + option.duration
+        } else if let Ok(expiration) = DateTime::parse_from_rfc3339(share_for) {
+            expiration.to_utc()
+        } else {
+            humantime::parse_duration(share_for)
+                .ok()
+                .and_then(|duration| TimeDelta::from_std(duration).ok())
+                .and_then(|duration| now.checked_add_signed(duration))
+                .ok_or_else(|| ExpirationError::Invalid(share_for.to_string()))?
+        };
+
+        if expiration < now + self.min_share_for {
+            Err(ExpirationError::TooSoon(format_duration(
+                self.min_share_for,
+            )))
+        } else if expiration > now + self.max_share_for {
+            Err(ExpirationError::TooLate(format_duration(
+                self.max_share_for,
+            )))
+        } else {
+            Ok(expiration)
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExpirationError {
+    #[error("Pick how long to share the file for.")]
+    Missing,
+    #[error(
+        "'{0}' is neither one of the options, a duration such as '2h 30m', nor a date and time \
+         such as '2024-08-01T12:00:00Z'."
+    )]
+    Invalid(String),
+    #[error("Files have to be shared for at least {0}.")]
+    TooSoon(String),
+    #[error("Files can be shared for at most {0}.")]
+    TooLate(String),
+}
+
+/// Formats a duration for humans, such as `3days 12h`.
+pub fn format_duration(duration: TimeDelta) -> String {
+    duration
+        .to_std()
+        .map(|duration| humantime::format_duration(duration).to_string())
+        .unwrap_or_else(|_| duration.to_string())
+}
+
+/// Durations are written for humans, such as `1h` or `3days`.
+mod duration {
+    use chrono::TimeDelta;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &TimeDelta,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let duration = duration.to_std().map_err(serde::ser::Error::custom)?;
+        serializer.collect_str(&humantime::format_duration(duration))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TimeDelta, D::Error> {
+        let duration = String::deserialize(deserializer)?;
+        humantime::parse_duration(&duration)
+            .map_err(D::Error::custom)
+            .and_then(|duration| TimeDelta::from_std(duration).map_err(D::Error::custom))
+    }
+}
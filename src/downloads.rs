@@ -0,0 +1,52 @@
+//! Shares can be limited to a number of downloads, which are counted in a file
+//! next to their manifest.
+
+use opendal::Operator;
+use relative_path::{RelativePath, RelativePathBuf};
+
+use crate::manifest::{lock_manifest, ManifestError};
+
+const DOWNLOADS_FILE_NAME: &str = "downloads.json";
+
+fn downloads_path(share_directory: &RelativePath) -> RelativePathBuf {
+    share_directory.join(DOWNLOADS_FILE_NAME)
+}
+
+/// How many times the share in `share_directory` has been downloaded.
+pub async fn read_downloads(
+    share_directory: &RelativePath,
+    storage: &Operator,
+) -> Result<u64, ManifestError> {
+    match storage.read(downloads_path(share_directory).as_str()).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(err) if err.kind() == opendal::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Counts a download of the share in `share_directory` if it has any left out
+/// of `max_downloads`, returning how many it has left after this one.
+///
+/// Like [`crate::manifest::record_part`], this is only atomic within a
+/// single instance.
+pub async fn claim_download(
+    share_directory: &RelativePath,
+    max_downloads: u64,
+    storage: &Operator,
+) -> Result<Option<u64>, ManifestError> {
+    let _guard = lock_manifest(share_directory).await;
+
+    let downloads = read_downloads(share_directory, storage).await?;
+    if downloads >= max_downloads {
+        return Ok(None);
+    }
+
+    storage
+        .write(
+            downloads_path(share_directory).as_str(),
+            serde_json::to_vec(&(downloads + 1))?,
+        )
+        .await?;
+
+    Ok(Some(max_downloads - downloads - 1))
+}
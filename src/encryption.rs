@@ -0,0 +1,338 @@
+use std::{fmt, io, ops::Range, str::FromStr};
+
+use axum::body::Bytes;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use bytes::BytesMut;
+use chacha20poly1305::{
+    aead::{
+        stream::{NewStream, Nonce, StreamBE32, StreamPrimitive},
+        OsRng,
+    },
+    KeyInit, XChaCha20Poly1305,
+};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::util::encode_checksum;
+
+/// Plaintext is encrypted in chunks of this size, so ranges of it can be
+/// decrypted without decrypting everything before them.
+const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+const ENCRYPTED_CHUNK_SIZE: u64 = CHUNK_SIZE + TAG_SIZE;
+/// Every encrypted file starts with the random nonce prefix it was encrypted with.
+pub const HEADER_SIZE: u64 = 19;
+
+type Cipher = StreamBE32<XChaCha20Poly1305>;
+
+/// The key a share is encrypted with at rest.
+///
+/// It is only ever handed out as part of the share's links, and never stored.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ShareKey(chacha20poly1305::Key);
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid key.")]
+pub struct InvalidShareKey;
+
+impl ShareKey {
+    pub fn generate() -> Self { Self(XChaCha20Poly1305::generate_key(&mut OsRng)) }
+
+    /// A digest of the key that can be stored to turn away the wrong key before
+    /// trying to decrypt anything with it.
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"share-key.");
+        hasher.update(self.0);
+        encode_checksum(hasher.finalize())
+    }
+
+    fn cipher(&self, nonce: &Nonce<XChaCha20Poly1305, Cipher>) -> Cipher {
+        Cipher::from_aead(XChaCha20Poly1305::new(&self.0), nonce)
+    }
+}
+
+impl fmt::Debug for ShareKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("ShareKey(..)") }
+}
+
+impl fmt::Display for ShareKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&BASE64_URL_SAFE_NO_PAD.encode(self.0))
+    }
+}
+
+impl FromStr for ShareKey {
+    type Err = InvalidShareKey;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let key = BASE64_URL_SAFE_NO_PAD
+            .decode(key)
+            .map_err(|_| InvalidShareKey)?;
+        chacha20poly1305::Key::from_exact_iter(key)
+            .map(Self)
+            .ok_or(InvalidShareKey)
+    }
+}
+
+impl TryFrom<String> for ShareKey {
+    type Error = InvalidShareKey;
+
+    fn try_from(key: String) -> Result<Self, Self::Error> { key.parse() }
+}
+
+impl From<ShareKey> for String {
+    fn from(key: ShareKey) -> Self { key.to_string() }
+}
+
+/// The `?key=` of a link to an encrypted share.
+#[derive(Deserialize)]
+pub struct KeyQuery {
+    pub key: Option<String>,
+}
+
+/// Encrypts `body` with XChaCha20-Poly1305 in the STREAM construction, which
+/// authenticates every chunk as well as their order and where the body ends.
+pub fn encrypt<S>(key: &ShareKey, body: S) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    let mut nonce = Nonce::<XChaCha20Poly1305, Cipher>::default();
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let cipher = key.cipher(&nonce);
+
+    let header = Bytes::copy_from_slice(&nonce);
+    stream::once(futures::future::ready(Ok(header))).chain(
+        chunked(body, CHUNK_SIZE as usize)
+            .enumerate()
+            .map(move |(position, chunk)| {
+                let (chunk, last) = chunk?;
+                let position = u32::try_from(position)
+                    .map_err(|_| io::Error::other("too many chunks to encrypt"))?;
+                cipher
+                    .encrypt(position, last, chunk.as_ref())
+                    .map(Bytes::from)
+                    .map_err(|_| io::Error::other("unable to encrypt chunk"))
+            }),
+    )
+}
+
+/// Decrypts the chunks in `body` which has to start on a chunk boundary, at
+/// `first_chunk`. Unless `reaches_end` the last chunk in `body` isn't expected
+/// to be the last chunk of the whole file.
+pub fn decrypt<S>(
+    key: &ShareKey,
+    header: &[u8],
+    first_chunk: u32,
+    reaches_end: bool,
+    body: S,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>>>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    let nonce = Nonce::<XChaCha20Poly1305, Cipher>::from_exact_iter(header.iter().copied())
+        .ok_or_else(|| io::Error::other("invalid encryption header"))?;
+    let cipher = key.cipher(&nonce);
+
+    Ok(chunked(body, ENCRYPTED_CHUNK_SIZE as usize)
+        .enumerate()
+        .map(move |(position, chunk)| {
+            let (chunk, last) = chunk?;
+            let position = u32::try_from(position)
+                .ok()
+                .and_then(|position| first_chunk.checked_add(position))
+                .ok_or_else(|| io::Error::other("too many chunks to decrypt"))?;
+            cipher
+                .decrypt(position, last && reaches_end, chunk.as_ref())
+                .map(Bytes::from)
+                .map_err(|_| io::Error::other("unable to decrypt chunk"))
+        }))
+}
+
+/// The encrypted chunks that hold the plaintext `window` of a file that is `size` bytes long.
+pub struct EncryptedWindow {
+    /// Where the chunks are in the encrypted file.
+    pub stored: Range<u64>,
+    pub first_chunk: u32,
+    pub reaches_end: bool,
+    /// Offset of the first chunk in the plaintext.
+    pub offset: u64,
+}
+
+pub fn encrypted_window(window: Range<u64>, size: u64) -> EncryptedWindow {
+    let last_chunk_of_file = size.saturating_sub(1) / CHUNK_SIZE;
+    let first_chunk = window.start / CHUNK_SIZE;
+    let last_chunk =
+        (window.end.saturating_sub(1) / CHUNK_SIZE).clamp(first_chunk, last_chunk_of_file);
+
+    let encrypted_size = HEADER_SIZE + size + (last_chunk_of_file + 1) * TAG_SIZE;
+    EncryptedWindow {
+        stored: HEADER_SIZE + first_chunk * ENCRYPTED_CHUNK_SIZE
+            ..(HEADER_SIZE + (last_chunk + 1) * ENCRYPTED_CHUNK_SIZE).min(encrypted_size),
+        first_chunk: first_chunk as u32,
+        reaches_end: last_chunk == last_chunk_of_file,
+        offset: first_chunk * CHUNK_SIZE,
+    }
+}
+
+/// Splits `body` up into chunks of exactly `chunk_size`, except for the last
+/// one, along with whether each chunk is the last one.
+fn chunked<S>(body: S, chunk_size: usize) -> impl Stream<Item = io::Result<(Bytes, bool)>>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    stream::try_unfold(
+        (Box::pin(body), BytesMut::new(), false),
+        move |(mut body, mut buffer, finished)| async move {
+            if finished {
+                return Ok(None);
+            }
+
+            // Stay a byte ahead of the chunk, so we know whether it's the last one.
+            while buffer.len() <= chunk_size {
+                match body.try_next().await? {
+                    Some(bytes) => buffer.extend_from_slice(&bytes),
+                    None => {
+                        let chunk = buffer.split().freeze();
+                        return Ok(Some(((chunk, true), (body, buffer, true))));
+                    }
+                }
+            }
+
+            let chunk = buffer.split_to(chunk_size).freeze();
+            Ok(Some(((chunk, false), (body, buffer, false))))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn plaintext(size: usize) -> Vec<u8> { (0..size).map(|byte| byte as u8).collect() }
+
+    /// The body split up unevenly, like it would come in over the network.
+    fn body(bytes: &[u8]) -> impl Stream<Item = io::Result<Bytes>> {
+        let chunks = bytes
+            .chunks(1000)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        stream::iter(chunks)
+    }
+
+    fn collect(body: impl Stream<Item = io::Result<Bytes>>) -> io::Result<Vec<u8>> {
+        block_on(body.try_fold(Vec::new(), |mut bytes, chunk| async move {
+            bytes.extend_from_slice(&chunk);
+            Ok(bytes)
+        }))
+    }
+
+    fn encrypt_all(key: &ShareKey, bytes: &[u8]) -> Vec<u8> {
+        collect(encrypt(key, body(bytes))).unwrap()
+    }
+
+    fn decrypt_window(
+        key: &ShareKey,
+        encrypted: &[u8],
+        window: Range<u64>,
+        size: u64,
+    ) -> io::Result<Vec<u8>> {
+        let encrypted_window = encrypted_window(window.clone(), size);
+        let stored = &encrypted
+            [encrypted_window.stored.start as usize..encrypted_window.stored.end as usize];
+        let decrypted = collect(decrypt(
+            key,
+            &encrypted[..HEADER_SIZE as usize],
+            encrypted_window.first_chunk,
+            encrypted_window.reaches_end,
+            body(stored),
+        )?)?;
+        let start = (window.start - encrypted_window.offset) as usize;
+        let end = (window.end - encrypted_window.offset) as usize;
+        Ok(decrypted[start..end].to_vec())
+    }
+
+    #[test]
+    fn round_trips() {
+        let key = ShareKey::generate();
+        for size in [0, 1, CHUNK_SIZE as usize, CHUNK_SIZE as usize * 3 + 5] {
+            let plaintext = plaintext(size);
+            let encrypted = encrypt_all(&key, &plaintext);
+            let chunks = (size as u64).saturating_sub(1) / CHUNK_SIZE + 1;
+            assert_eq!(
+                encrypted.len() as u64,
+                HEADER_SIZE + size as u64 + chunks * TAG_SIZE
+            );
+
+            let decrypted = decrypt_window(&key, &encrypted, 0..size as u64, size as u64).unwrap();
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn windows_decrypt_on_their_own() {
+        let key = ShareKey::generate();
+        let size = CHUNK_SIZE * 3 + 5;
+        let plaintext = plaintext(size as usize);
+        let encrypted = encrypt_all(&key, &plaintext);
+
+        for window in [
+            0..1,
+            CHUNK_SIZE - 1..CHUNK_SIZE + 1,
+            CHUNK_SIZE..CHUNK_SIZE * 2,
+            CHUNK_SIZE * 2 + 7..size,
+            size - 1..size,
+        ] {
+            let decrypted = decrypt_window(&key, &encrypted, window.clone(), size).unwrap();
+            assert_eq!(
+                decrypted,
+                plaintext[window.start as usize..window.end as usize]
+            );
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let key = ShareKey::generate();
+        let size = CHUNK_SIZE * 2;
+        let mut encrypted = encrypt_all(&key, &plaintext(size as usize));
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+
+        assert!(decrypt_window(&key, &encrypted, 0..size, size).is_err());
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let key = ShareKey::generate();
+        let size = CHUNK_SIZE * 2;
+        let encrypted = encrypt_all(&key, &plaintext(size as usize));
+
+        // Only the first chunk, passed off as the whole file.
+        let truncated = &encrypted[..(HEADER_SIZE + ENCRYPTED_CHUNK_SIZE) as usize];
+        assert!(decrypt_window(&key, truncated, 0..CHUNK_SIZE, CHUNK_SIZE).is_err());
+    }
+
+    #[test]
+    fn the_wrong_key_is_rejected() {
+        let encrypted = encrypt_all(&ShareKey::generate(), &plaintext(10));
+
+        assert!(decrypt_window(&ShareKey::generate(), &encrypted, 0..10, 10).is_err());
+    }
+
+    #[test]
+    fn keys_round_trip_through_links() {
+        let key = ShareKey::generate();
+        let parsed = key.to_string().parse::<ShareKey>().unwrap();
+
+        assert_eq!(parsed.digest(), key.digest());
+        assert_ne!(ShareKey::generate().digest(), key.digest());
+        assert!("not a key".parse::<ShareKey>().is_err());
+        assert!("AAAA".parse::<ShareKey>().is_err());
+    }
+}
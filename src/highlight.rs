@@ -0,0 +1,107 @@
+//! Text files are highlighted on the server, so the viewer is just static
+//! HTML with classes that `public/highlight.css` gives colors to.
+
+use std::sync::LazyLock;
+
+use maud::html;
+use relative_path::RelativePath;
+use syntect::{
+    html::{ClassStyle, ClassedHTMLGenerator},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+static SYNTAX_SET: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Highlighting takes too long for text any larger than this, which is shown
+/// as is instead.
+const MAX_HIGHLIGHT_SIZE: usize = 512 * 1024;
+
+/// The HTML for each line of `content`, highlighted as whatever language the
+/// extension of `file_name` is for. If it doesn't have one (or it isn't for
+/// any language), the language is detected from the first line, such as a
+/// shebang.
+///
+/// Each line is whole on its own, so they can be wrapped in whatever.
+///
+/// This can take a while, so it is done off of the async runtime.
+pub async fn highlight_lines(content: String, file_name: String) -> anyhow::Result<Vec<String>> {
+    Ok(tokio::task::spawn_blocking(move || highlight_lines_blocking(&content, &file_name)).await?)
+}
+
+fn highlight_lines_blocking(content: &str, file_name: &str) -> Vec<String> {
+    let highlighted = find_syntax(content, file_name)
+        .filter(|_| content.len() <= MAX_HIGHLIGHT_SIZE)
+        .and_then(|syntax| {
+            let mut generator =
+                ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAX_SET, CLASS_STYLE);
+            for line in LinesWithEndings::from(content) {
+                generator
+                    .parse_html_for_line_which_includes_newline(line)
+                    .inspect_err(|err| tracing::warn!("Unable to highlight {file_name}: {err}"))
+                    .ok()?;
+            }
+            Some(generator.finalize())
+        });
+
+    match highlighted {
+        Some(html) => {
+            let mut lines = split_lines(&html);
+            lines.truncate(content.lines().count());
+            lines
+        }
+        None => content
+            .lines()
+            .map(|line| html! { (line) }.into_string())
+            .collect(),
+    }
+}
+
+fn find_syntax(content: &str, file_name: &str) -> Option<&'static SyntaxReference> {
+    let path = RelativePath::new(file_name);
+    path.extension()
+        .and_then(|extension| SYNTAX_SET.find_syntax_by_extension(extension))
+        // Some files are known by their whole name, like `Makefile`.
+        .or_else(|| SYNTAX_SET.find_syntax_by_extension(path.file_name()?))
+        .or_else(|| SYNTAX_SET.find_syntax_by_first_line(content))
+        .filter(|syntax| syntax.name != "Plain Text")
+}
+
+/// Splits the highlighted HTML of a whole file into lines, closing whatever is
+/// still open at the end of each line and opening it again on the next.
+///
+/// The HTML is only ever escaped text and `span`s, which is all this handles.
+fn split_lines(html: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut open_tags: Vec<&str> = Vec::new();
+    let mut line = String::new();
+
+    let mut rest = html;
+    while let Some(index) = rest.find(['<', '\n']) {
+        line.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        if let Some(after_newline) = rest.strip_prefix('\n') {
+            line.push_str(&"</span>".repeat(open_tags.len()));
+            lines.push(std::mem::take(&mut line));
+            line.extend(open_tags.iter().copied());
+            rest = after_newline;
+        } else {
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            let tag = &rest[..end];
+            if tag.starts_with("</") {
+                open_tags.pop();
+            } else {
+                open_tags.push(tag);
+            }
+            line.push_str(tag);
+            rest = &rest[end..];
+        }
+    }
+    line.push_str(rest);
+    lines.push(line);
+
+    lines
+}
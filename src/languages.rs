@@ -0,0 +1,43 @@
+//! Text can be pasted instead of uploading a file, along with a hint of what
+//! language it is in. The hint ends up as the extension of the name the text
+//! is shared under, which is what it is highlighted by.
+
+/// The languages offered for pasted text, as their name and extension. The
+/// first one is the default.
+pub const LANGUAGES: &[(&str, &str)] = &[
+    ("Plain text", "txt"),
+    ("Bash", "sh"),
+    ("C", "c"),
+    ("C++", "cpp"),
+    ("C#", "cs"),
+    ("CSS", "css"),
+    ("Diff", "diff"),
+    ("Go", "go"),
+    ("HTML", "html"),
+    ("Java", "java"),
+    ("JavaScript", "js"),
+    ("JSON", "json"),
+    ("Kotlin", "kt"),
+    ("Log", "log"),
+    ("Lua", "lua"),
+    ("Markdown", "md"),
+    ("PHP", "php"),
+    ("Python", "py"),
+    ("Ruby", "rb"),
+    ("Rust", "rs"),
+    ("SQL", "sql"),
+    ("TOML", "toml"),
+    ("TypeScript", "ts"),
+    ("XML", "xml"),
+    ("YAML", "yaml"),
+];
+
+/// The extension for the language `hint`, which is either the name of one of
+/// the [`LANGUAGES`] or its extension.
+pub fn language_extension(hint: &str) -> Option<&'static str> {
+    let hint = hint.trim();
+    LANGUAGES
+        .iter()
+        .find(|(name, extension)| name.eq_ignore_ascii_case(hint) || *extension == hint)
+        .map(|(_, extension)| *extension)
+}
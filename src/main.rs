@@ -1,26 +1,41 @@
-use std::{path::PathBuf, str::FromStr};
-
-use axum::{extract::DefaultBodyLimit, routing::get, Router};
-use chrono::Utc;
-use cleanup::cleanup;
-use opendal::Operator;
-use routes::not_found::not_found;
+#[cfg(feature = "standalone")]
+use clap::Parser;
+#[cfg(not(feature = "standalone"))]
+use config::Config;
 use service::TempShareService;
-use tokio_cron_scheduler::{Job, JobScheduler};
-use tower_http::{services::ServeDir, trace::TraceLayer};
+#[cfg(not(feature = "standalone"))]
+use session::Signer;
+#[cfg(not(feature = "standalone"))]
+use state::Settings;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
+mod archive;
+mod blobs;
 mod cleanup;
+mod collection;
 mod components;
+mod compression;
+mod config;
+mod downloads;
+mod encryption;
+mod highlight;
+mod languages;
+mod manifest;
+mod owner;
+mod parts;
+mod password;
+mod redirects;
 mod routes;
 mod service;
+mod session;
+#[cfg(feature = "standalone")]
+mod standalone;
+mod state;
+mod tombstone;
 mod util;
 
-#[shuttle_runtime::main]
-async fn main(
-    #[shuttle_opendal::Opendal(scheme = env!("OPENDAL_SCHEME"))] storage: Operator,
-) -> Result<TempShareService, shuttle_runtime::Error> {
+fn init_tracing() {
     let format = tracing_subscriber::fmt::format().without_time().compact();
     tracing_subscriber::fmt()
         .event_format(format)
@@ -31,55 +46,63 @@ async fn main(
         )
         .init();
     tracing::info!("Tracing is initialized!");
+}
 
-    // TODO: Move this setup into a constructor for the `TempShareService`.
-    let router = Router::new()
-        .route("/", get(routes::index::get).post(routes::index::post))
-        .route(
-            "/file/:file_name",
-            get(routes::file::index::get).post(routes::file::index::post),
-        )
-        .layer(DefaultBodyLimit::disable())
-        .route("/file/:file_name/view", get(routes::file::view::get))
-        .nest_service(
-            "/public",
-            ServeDir::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("public")),
-        )
-        .layer(TraceLayer::new_for_http())
-        .with_state(storage.clone())
-        .fallback(not_found);
+#[cfg(not(feature = "standalone"))]
+#[shuttle_runtime::main]
+async fn main(
+    #[shuttle_opendal::Opendal(scheme = env!("OPENDAL_SCHEME"))] storage: opendal::Operator,
+    #[shuttle_runtime::Secrets] secrets: shuttle_runtime::SecretStore,
+) -> Result<TempShareService, shuttle_runtime::Error> {
+    init_tracing();
 
-    let scheduler = JobScheduler::new()
-        .await
-        .map_err(|err| shuttle_runtime::Error::BuildPanic(err.to_string()))?;
+    let signer = match secrets.get("UPLOAD_SESSION_SECRET") {
+        Some(secret) => Signer::new(secret.as_bytes()),
+        None => {
+            tracing::warn!(
+                "No UPLOAD_SESSION_SECRET secret set, uploads in progress won't survive a restart"
+            );
+            Signer::random()
+        }
+    };
 
-    let cron_schedule = if cfg!(debug_assertions) {
-        "0/30 * * * * *"
-    } else {
-        "0 0/30 * * * *"
+    let settings = Settings {
+        deduplicate: secrets
+            .get("DEDUPLICATE")
+            .is_some_and(|deduplicate| deduplicate == "true"),
+        compress: secrets
+            .get("COMPRESS")
+            .is_some_and(|compress| compress == "true"),
+        encrypt: secrets
+            .get("ENCRYPT")
+            .is_some_and(|encrypt| encrypt == "true"),
     };
 
-    let schedule = cron::Schedule::from_str(cron_schedule).unwrap();
-    tracing::info!("Upcoming fire times:");
-    schedule
-        .upcoming(Utc)
-        .take(10)
-        .for_each(|time| tracing::info!("-> {}", time));
+    let config = Config::load(
+        secrets
+            .get("CONFIG_FILE")
+            .unwrap_or_else(|| "config.toml".into()),
+    )
+    .map_err(|err| shuttle_runtime::Error::BuildPanic(err.to_string()))?;
 
-    scheduler
-        .add(
-            Job::new_async(cron_schedule, move |_uuid, _l| {
-                let storage = storage.clone(); // Clone storage just for this task
-                Box::pin(async move {
-                    if let Err(err) = cleanup(storage).await {
-                        tracing::error!("{err}");
-                    }
-                })
-            })
-            .map_err(|err| shuttle_runtime::Error::BuildPanic(err.to_string()))?,
-        )
+    TempShareService::new(storage, signer, settings, config)
         .await
-        .map_err(|err| shuttle_runtime::Error::BuildPanic(err.to_string()))?;
+        .map_err(|err| shuttle_runtime::Error::BuildPanic(err.to_string()))
+}
+
+#[cfg(feature = "standalone")]
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = standalone::Args::parse();
+    init_tracing();
+
+    let storage = args.storage.operator()?;
+    let config = config::Config::load(&args.config)?;
+    let service = TempShareService::new(storage, args.signer(), args.settings(), config).await?;
+
+    let tcp_listener = tokio::net::TcpListener::bind(args.bind).await?;
+    tracing::info!("Listening on {}", args.bind);
+    service.serve(tcp_listener).await;
 
-    Ok(TempShareService { router, scheduler })
+    Ok(())
 }
@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex, Weak},
+};
+
+use futures::TryStreamExt;
+use mime_guess::Mime;
+use opendal::Operator;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::{
+    blobs::{blob_directory, store_blob, BlobError},
+    compression::Codec,
+    encryption::ShareKey,
+    parts::{part_path, parts_size, read_parts_range, FilePart},
+    util::{encode_checksum, sanitize_file_name, WrittenFile},
+};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Everything we know about an upload, stored next to its parts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub original_name: String,
+    /// Total size of the file in bytes.
+    pub size: u64,
+    /// One entry per expected part, which is filled in once that part lands.
+    pub parts: Vec<Option<PartManifest>>,
+    /// Base64 encoded SHA-256 digest of the whole file, once it is finalized.
+    pub checksum: Option<String>,
+    /// Whether every part has landed and the file can be downloaded.
+    #[serde(default)]
+    pub finalized: bool,
+    /// Key of the content addressed blob holding the parts, if they were deduplicated.
+    #[serde(default)]
+    pub blob: Option<String>,
+    /// Digest of the key the parts are encrypted with, if they are, see [`ShareKey::digest`].
+    #[serde(default)]
+    pub key_digest: Option<String>,
+    /// Whether the parts were encrypted in the browser before being uploaded,
+    /// in which case only the browser can make sense of them.
+    #[serde(default)]
+    pub end_to_end_encrypted: bool,
+    /// How many times the file can be downloaded before it is removed, if it
+    /// is limited, see [`crate::downloads`].
+    #[serde(default)]
+    pub max_downloads: Option<u64>,
+    /// Digest of the token whoever shared the file can manage it with, see
+    /// [`crate::owner::OwnerToken`].
+    #[serde(default)]
+    pub owner_token_digest: Option<String>,
+    /// Argon2 hash of the password the file is protected with, if it is, see
+    /// [`crate::password`].
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    /// Media type detected from the first bytes of the file, see
+    /// [`crate::util::sniff_content_type`]. Files shared before types were
+    /// detected don't have one.
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartManifest {
+    /// Size of the part in bytes, before any compression.
+    pub size: u64,
+    /// Base64 encoded SHA-256 digest of the part, before any compression.
+    ///
+    /// This isn't kept for encrypted parts, since it would let anyone with
+    /// access to storage confirm what the part contains.
+    pub checksum: Option<String>,
+    /// How the part is compressed at rest, if at all.
+    #[serde(default)]
+    pub codec: Option<Codec>,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Size of the part in storage, if it differs from `size` due to compression
+    /// or encryption.
+    #[serde(default, alias = "compressed_size")]
+    pub stored_size: Option<u64>,
+}
+
+impl PartManifest {
+    /// Size of the part as it is in storage.
+    pub fn size_in_storage(&self) -> u64 { self.stored_size.unwrap_or(self.size) }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("This file is encrypted, and requires a key to access.")]
+    Missing,
+    #[error("The key for this file is invalid.")]
+    Invalid,
+}
+
+impl Manifest {
+    pub fn new(original_name: String, size: u64, parts: usize) -> Self {
+        Self {
+            original_name,
+            size,
+            parts: vec![None; parts],
+            checksum: None,
+            finalized: false,
+            blob: None,
+            key_digest: None,
+            end_to_end_encrypted: false,
+            max_downloads: None,
+            owner_token_digest: None,
+            password_hash: None,
+            content_type: None,
+        }
+    }
+
+    /// The name the file was uploaded with, made safe to show and hand back out,
+    /// or `file_name` (which it is shared under) if there's nothing left of it.
+    pub fn display_name(&self, file_name: &RelativePath) -> String {
+        sanitize_file_name(&self.original_name).unwrap_or_else(|| file_name.to_string())
+    }
+
+    /// Media type of the file as detected when it was uploaded, or for files
+    /// shared before types were detected guessed from the extension of the
+    /// name it was uploaded with (or else the `file_name` it is shared under).
+    pub fn media_type(&self, file_name: &RelativePath) -> Option<Mime> {
+        match &self.content_type {
+            Some(content_type) => content_type.parse().ok(),
+            // Shares only keep plain extensions, which the original name might
+            // not have had.
+            None => RelativePath::new(&self.original_name)
+                .extension()
+                .or_else(|| file_name.extension())
+                .and_then(|extension| mime_guess::from_ext(extension).first()),
+        }
+    }
+
+    /// Checks that `key` is the one this file is encrypted with, returning the
+    /// key to decrypt it with if it is encrypted.
+    pub fn check_key(&self, key: Option<&str>) -> Result<Option<ShareKey>, KeyError> {
+        let Some(key_digest) = &self.key_digest else {
+            return Ok(None);
+        };
+
+        let key = key
+            .ok_or(KeyError::Missing)?
+            .parse::<ShareKey>()
+            .map_err(|_| KeyError::Invalid)?;
+        if *key_digest == key.digest() {
+            Ok(Some(key))
+        } else {
+            Err(KeyError::Invalid)
+        }
+    }
+
+    /// The parts of the file stored in `share_directory` (or the blob it
+    /// references), or `None` if any of them have yet to land.
+    pub fn file_parts(&self, share_directory: &RelativePath) -> Option<Vec<FilePart>> {
+        let directory = match &self.blob {
+            Some(key) => blob_directory(key),
+            None => share_directory.to_owned(),
+        };
+
+        self.parts
+            .iter()
+            .enumerate()
+            .map(|(part, part_manifest)| {
+                part_manifest.as_ref().map(|part_manifest| FilePart {
+                    path: part_path(&directory, part).to_string(),
+                    size: part_manifest.size,
+                    codec: part_manifest.codec,
+                    encrypted: part_manifest.encrypted,
+                    stored_size: part_manifest.size_in_storage(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ManifestError {
+    #[error("Manifest not found.")]
+    NotFound,
+    #[error("Unable to access manifest: {0}")]
+    Storage(opendal::Error),
+    #[error("Invalid manifest: {0}")]
+    Invalid(#[from] serde_json::Error),
+    #[error("Unable to read parts: {0}")]
+    UnableToReadParts(std::io::Error),
+    #[error("Missing parts: {0:?}")]
+    MissingParts(Vec<usize>),
+    #[error("Manifest has already been finalized.")]
+    AlreadyFinalized,
+    #[error(transparent)]
+    Blob(#[from] BlobError),
+}
+
+impl From<opendal::Error> for ManifestError {
+    fn from(error: opendal::Error) -> Self {
+        match error.kind() {
+            opendal::ErrorKind::NotFound => ManifestError::NotFound,
+            _ => ManifestError::Storage(error),
+        }
+    }
+}
+
+fn manifest_path(share_directory: &RelativePath) -> RelativePathBuf {
+    share_directory.join(MANIFEST_FILE_NAME)
+}
+
+pub async fn read_manifest(
+    share_directory: &RelativePath,
+    storage: &Operator,
+) -> Result<Manifest, ManifestError> {
+    let bytes = storage
+        .read(manifest_path(share_directory).as_str())
+        .await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+pub async fn write_manifest(
+    share_directory: &RelativePath,
+    manifest: &Manifest,
+    storage: &Operator,
+) -> Result<(), ManifestError> {
+    let bytes = serde_json::to_vec(manifest)?;
+    storage
+        .write_with(manifest_path(share_directory).as_str(), bytes)
+        .content_type("application/json")
+        .await?;
+    Ok(())
+}
+
+/// Records a part that has finished landing, and the type of the file if it
+/// is the first one and the type isn't already known.
+///
+/// Parts land concurrently, so the manifest is locked while it is updated to
+/// avoid one part clobbering another. This only holds within a single instance.
+/// Parts that land once it has been finalized are rejected, since they might
+/// have already been moved into a blob with another layout.
+pub async fn record_part(
+    share_directory: &RelativePath,
+    part: usize,
+    written_part: WrittenFile,
+    storage: &Operator,
+) -> Result<Manifest, ManifestError> {
+    let _guard = lock_manifest(share_directory).await;
+
+    let mut manifest = read_manifest(share_directory, storage).await?;
+    if manifest.finalized {
+        return Err(ManifestError::AlreadyFinalized);
+    }
+    if let Some(part_manifest) = manifest.parts.get_mut(part) {
+        *part_manifest = Some(PartManifest {
+            size: written_part.size,
+            checksum: (!written_part.encrypted).then_some(written_part.checksum),
+            codec: written_part.codec,
+            encrypted: written_part.encrypted,
+            stored_size: (written_part.stored_size != written_part.size)
+                .then_some(written_part.stored_size),
+        });
+    }
+    // End-to-end encrypted parts are just noise to us.
+    if part == 0 && !manifest.end_to_end_encrypted && manifest.content_type.is_none() {
+        manifest.content_type = Some(written_part.content_type);
+    }
+    write_manifest(share_directory, &manifest, storage).await?;
+
+    Ok(manifest)
+}
+
+/// Checks that every expected part has landed, and if so records the size and
+/// checksum of the whole file and marks it as downloadable.
+///
+/// When `deduplicate` is set the parts are moved into the content addressed
+/// blob for the file, see [`store_blob`].
+///
+/// The manifest stays locked throughout, so no part can be recorded while the
+/// parts are being checked or moved.
+pub async fn finalize_manifest(
+    share_directory: &RelativePath,
+    deduplicate: bool,
+    storage: &Operator,
+) -> Result<Manifest, ManifestError> {
+    let _guard = lock_manifest(share_directory).await;
+
+    let mut manifest = read_manifest(share_directory, storage).await?;
+    if manifest.finalized {
+        return Ok(manifest);
+    }
+
+    let mut missing_parts = Vec::new();
+    for (part, part_manifest) in manifest.parts.iter().enumerate() {
+        let Some(part_manifest) = part_manifest else {
+            missing_parts.push(part);
+            continue;
+        };
+
+        // Don't just trust the manifest, the part has to actually be there too.
+        match storage
+            .stat(part_path(share_directory, part).as_str())
+            .await
+        {
+            Ok(metadata) if metadata.content_length() == part_manifest.size_in_storage() => {}
+            Ok(_) => missing_parts.push(part),
+            Err(err) if err.kind() == opendal::ErrorKind::NotFound => missing_parts.push(part),
+            Err(err) => return Err(ManifestError::Storage(err)),
+        }
+    }
+
+    let parts = match manifest.file_parts(share_directory) {
+        Some(parts) if missing_parts.is_empty() => parts,
+        _ => return Err(ManifestError::MissingParts(missing_parts)),
+    };
+
+    // A single part is the whole file, otherwise the parts have to be read back
+    // in order since they could have landed in any order. Encrypted files don't
+    // get a checksum for the same reason their parts don't.
+    let checksum = match manifest.parts.as_slice() {
+        _ if manifest.key_digest.is_some() => None,
+        [Some(part_manifest)] => part_manifest.checksum.clone(),
+        _ => {
+            let hasher = read_parts_range(&parts, 0..parts_size(&parts), None, storage)
+                .try_fold(Sha256::new(), |mut hasher, bytes| async move {
+                    hasher.update(&bytes);
+                    Ok(hasher)
+                })
+                .await
+                .map_err(ManifestError::UnableToReadParts)?;
+            Some(encode_checksum(hasher.finalize()))
+        }
+    };
+
+    // Blobs are shared between shares, so encrypted files can't be deduplicated.
+    // End-to-end encrypted files never have the same contents anyways.
+    let blob = match &checksum {
+        Some(checksum) if deduplicate && !manifest.end_to_end_encrypted => {
+            Some(store_blob(share_directory, &manifest, checksum, storage).await?)
+        }
+        _ => None,
+    };
+
+    manifest.size = parts_size(&parts);
+    manifest.checksum = checksum;
+    manifest.finalized = true;
+    if let Some((key, blob_manifest)) = blob {
+        manifest.parts = blob_manifest.parts.into_iter().map(Some).collect();
+        manifest.blob = Some(key);
+    }
+    write_manifest(share_directory, &manifest, storage).await?;
+
+    Ok(manifest)
+}
+
+type ManifestLocks = Mutex<HashMap<RelativePathBuf, Weak<tokio::sync::Mutex<()>>>>;
+
+static MANIFEST_LOCKS: LazyLock<ManifestLocks> = LazyLock::new(Default::default);
+
+/// Locks the manifest in `directory` for the lifetime of the returned guard.
+pub async fn lock_manifest(directory: &RelativePath) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = MANIFEST_LOCKS
+            .lock()
+            .expect("manifest locks are not poisoned");
+        // Drop any locks nobody is holding anymore so this doesn't grow forever.
+        locks.retain(|_, lock| lock.strong_count() > 0);
+
+        match locks.get(directory).and_then(Weak::upgrade) {
+            Some(lock) => lock,
+            None => {
+                let lock = Arc::new(tokio::sync::Mutex::new(()));
+                locks.insert(directory.to_owned(), Arc::downgrade(&lock));
+                lock
+            }
+        }
+    };
+
+    lock.lock_owned().await
+}
+
+#[cfg(test)]
+mod tests {
+    use opendal::services;
+
+    use super::*;
+
+    const CONTENT: &[u8] = b"Hello, world!";
+
+    fn written_part(content: &[u8]) -> WrittenFile {
+        WrittenFile {
+            size: content.len() as u64,
+            checksum: encode_checksum(Sha256::digest(content)),
+            codec: None,
+            encrypted: false,
+            stored_size: content.len() as u64,
+            content_type: "text/plain".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn parts_are_rejected_once_finalized() {
+        let storage = Operator::new(services::Memory::default()).unwrap().finish();
+        let share_directory = RelativePath::new("1/share.txt");
+        let manifest = Manifest::new("hello.txt".to_string(), CONTENT.len() as u64, 1);
+        write_manifest(share_directory, &manifest, &storage)
+            .await
+            .unwrap();
+
+        storage
+            .write(part_path(share_directory, 0).as_str(), CONTENT)
+            .await
+            .unwrap();
+        record_part(share_directory, 0, written_part(CONTENT), &storage)
+            .await
+            .unwrap();
+        let finalized = finalize_manifest(share_directory, true, &storage)
+            .await
+            .unwrap();
+        assert!(finalized.blob.is_some());
+
+        let late_part = record_part(share_directory, 0, written_part(b"Bye"), &storage).await;
+        assert!(matches!(late_part, Err(ManifestError::AlreadyFinalized)));
+        let manifest = read_manifest(share_directory, &storage).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(manifest.parts).unwrap(),
+            serde_json::to_value(finalized.parts).unwrap()
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::util::encode_checksum;
+
+/// Name of the cookie the owner token is handed back in after an upload.
+pub const OWNER_TOKEN_COOKIE: &str = "owner-token";
+
+/// A secret that proves whoever presents it is the one who shared a file, which
+/// lets them manage the share.
+///
+/// It is only ever handed out once when the upload starts, and never stored.
+#[derive(Clone)]
+pub struct OwnerToken(String);
+
+impl OwnerToken {
+    pub fn generate() -> Self {
+        let mut token = [0; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        Self(BASE64_URL_SAFE_NO_PAD.encode(token))
+    }
+
+    /// A digest of the token that can be stored to check tokens against.
+    pub fn digest(&self) -> String { digest(&self.0) }
+
+    /// Hands the token back to the browser that started the upload, for only
+    /// the view page of `file_name` to pick up once.
+    pub fn cookie(&self, file_name: &str) -> Cookie<'static> {
+        Cookie::build((OWNER_TOKEN_COOKIE, self.0.clone()))
+            .path(view_path(file_name))
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .build()
+    }
+}
+
+impl fmt::Debug for OwnerToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("OwnerToken(..)") }
+}
+
+impl fmt::Display for OwnerToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(&self.0) }
+}
+
+/// Whether `token` is the one `owner_token_digest` is the digest of.
+pub fn check_owner_token(owner_token_digest: Option<&str>, token: &str) -> bool {
+    owner_token_digest.is_some_and(|owner_token_digest| owner_token_digest == digest(token))
+}
+
+/// The cookie [`OwnerToken::cookie`] sets, so it can be removed again.
+pub fn removal_cookie(file_name: &str) -> Cookie<'static> {
+    Cookie::build(OWNER_TOKEN_COOKIE)
+        .path(view_path(file_name))
+        .build()
+}
+
+/// Link to manage `file_name`, which has to include its owner token.
+pub fn manage_url(file_name: &str, token: &str) -> String {
+    format!("/file/{file_name}/manage?token={token}")
+}
+
+fn view_path(file_name: &str) -> String { format!("/file/{file_name}/view") }
+
+fn digest(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"owner-token.");
+    hasher.update(token.as_bytes());
+    encode_checksum(hasher.finalize())
+}
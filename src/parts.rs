@@ -0,0 +1,169 @@
+use std::{io, ops::Range};
+
+use axum::body::Bytes;
+use futures::{stream, stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use opendal::Operator;
+use relative_path::{RelativePath, RelativePathBuf};
+
+use crate::{
+    compression::{decompress, Codec},
+    encryption::{decrypt, encrypted_window, ShareKey, HEADER_SIZE},
+};
+
+/// A single numbered part of an uploaded file.
+#[derive(Clone)]
+pub struct FilePart {
+    pub path: String,
+    /// Size of the part, before any compression or encryption.
+    pub size: u64,
+    pub codec: Option<Codec>,
+    pub encrypted: bool,
+    /// Size of the part as it is in storage.
+    pub stored_size: u64,
+}
+
+/// Path of the numbered `part` of the file stored in `share_directory`.
+pub fn part_path(share_directory: &RelativePath, part: usize) -> RelativePathBuf {
+    share_directory.join(part.to_string())
+}
+
+/// The combined size of every part.
+pub fn parts_size(parts: &[FilePart]) -> u64 { parts.iter().map(|part| part.size).sum() }
+
+/// Streams the bytes of `range` (relative to the whole file) out of the parts
+/// that overlap it, decrypting them with `key` if they are encrypted.
+///
+/// Parts outside of the range are never opened, and the parts that are opened
+/// are only read for the slice of them that falls within the range (give or
+/// take the encrypted chunks it falls in). Compressed parts can't be read from
+/// the middle, so those are decompressed from their start and the bytes before
+/// the slice skipped.
+pub fn read_parts_range(
+    parts: &[FilePart],
+    range: Range<u64>,
+    key: Option<&ShareKey>,
+    storage: &Operator,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let mut part_start = 0;
+    let windows = parts
+        .iter()
+        .filter_map(|part| {
+            let offset = part_start;
+            part_start += part.size;
+
+            let start = range.start.max(offset);
+            let end = range.end.min(part_start);
+            (start < end).then(|| (part.clone(), start - offset..end - offset))
+        })
+        .collect::<Vec<_>>();
+
+    let storage = storage.clone();
+    let key = key.cloned();
+    stream::iter(windows)
+        .then(move |(part, window)| read_part_range(part, window, key.clone(), storage.clone()))
+        .try_flatten()
+}
+
+async fn read_part_range(
+    part: FilePart,
+    window: Range<u64>,
+    key: Option<ShareKey>,
+    storage: Operator,
+) -> io::Result<BoxStream<'static, io::Result<Bytes>>> {
+    let read = |range: Range<u64>| async {
+        storage
+            .reader_with(&part.path)
+            .range(range)
+            .await
+            .map_err(io::Error::other)
+    };
+
+    let body = match (part.encrypted, part.codec) {
+        (false, None) => read(window).await?.boxed(),
+        (false, Some(codec)) => {
+            slice(decompress(codec, read(0..part.stored_size).await?), window).boxed()
+        }
+        (true, codec) => {
+            let key =
+                key.ok_or_else(|| io::Error::other("part is encrypted, but no key was given"))?;
+            let header = storage
+                .read_with(&part.path)
+                .range(0..HEADER_SIZE)
+                .await
+                .map_err(io::Error::other)?;
+
+            match codec {
+                // Only the encrypted chunks the window falls within have to be read.
+                None => {
+                    let encrypted = encrypted_window(window.clone(), part.size);
+                    let body = read(encrypted.stored).await?;
+                    let window = window.start - encrypted.offset..window.end - encrypted.offset;
+                    slice(
+                        decrypt(
+                            &key,
+                            &header,
+                            encrypted.first_chunk,
+                            encrypted.reaches_end,
+                            body,
+                        )?,
+                        window,
+                    )
+                    .boxed()
+                }
+                Some(codec) => {
+                    let body = read(HEADER_SIZE..part.stored_size).await?;
+                    slice(
+                        decompress(codec, decrypt(&key, &header, 0, true, body)?),
+                        window,
+                    )
+                    .boxed()
+                }
+            }
+        }
+    };
+
+    Ok(body)
+}
+
+/// Streams every part exactly as it is in storage, so compressed parts are
+/// left compressed.
+pub fn read_parts_stored(
+    parts: &[FilePart],
+    storage: &Operator,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let paths = parts
+        .iter()
+        .map(|part| part.path.clone())
+        .collect::<Vec<_>>();
+
+    let storage = storage.clone();
+    stream::iter(paths)
+        .then(move |path| {
+            let storage = storage.clone();
+            async move { storage.reader(&path).await.map_err(io::Error::other) }
+        })
+        .try_flatten()
+}
+
+/// Only lets the bytes of `window` through, stopping once past it.
+fn slice<S>(body: S, window: Range<u64>) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    body.scan(0, move |offset, bytes| {
+        let bytes = match bytes {
+            Err(err) => Some(Some(Err(err))),
+            Ok(_) if *offset >= window.end => None,
+            Ok(bytes) => {
+                let start = *offset;
+                *offset += bytes.len() as u64;
+
+                let slice_start = (window.start.clamp(start, *offset) - start) as usize;
+                let slice_end = (window.end.clamp(start, *offset) - start) as usize;
+                Some((slice_start < slice_end).then(|| Ok(bytes.slice(slice_start..slice_end))))
+            }
+        };
+        futures::future::ready(bytes)
+    })
+    .filter_map(futures::future::ready)
+}
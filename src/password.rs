@@ -0,0 +1,158 @@
+//! Shares can be protected with a password, which has to be entered before the
+//! file can be viewed or downloaded. Entering it hands out a short lived unlock
+//! cookie, so it doesn't have to be entered for every request.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::session::{Claims, Signer};
+
+/// Name of the cookie a share is unlocked with.
+pub const UNLOCK_COOKIE: &str = "unlock";
+
+/// How many wrong passwords can be tried for a share within
+/// [`failed_attempts_window`] before it stops accepting any.
+const MAX_FAILED_ATTEMPTS: u32 = 5;
+
+fn failed_attempts_window() -> TimeDelta { TimeDelta::minutes(15) }
+
+/// How long a share stays unlocked for after the password is entered.
+fn unlock_duration() -> TimeDelta { TimeDelta::hours(1) }
+
+/// Hashes `password` with Argon2 and a random salt, into a PHC string that
+/// holds everything needed to verify it.
+///
+/// This is slow on purpose, so it is done off of the async runtime.
+pub async fn hash_password(password: String) -> anyhow::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| anyhow::anyhow!("Unable to hash password: {err}"))
+    })
+    .await?
+}
+
+async fn verify_password(password_hash: String, password: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash).is_ok_and(|password_hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &password_hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UnlockError {
+    #[error("Wrong password.")]
+    WrongPassword,
+    #[error("Too many wrong passwords, try again in a few minutes.")]
+    TooManyAttempts,
+}
+
+struct FailedAttempts {
+    count: u32,
+    since: DateTime<Utc>,
+}
+
+/// Wrong passwords tried for each share, which only holds within a single
+/// instance.
+static FAILED_ATTEMPTS: LazyLock<Mutex<HashMap<RelativePathBuf, FailedAttempts>>> =
+    LazyLock::new(Default::default);
+
+/// Counts an attempt at the password for `file_name` up front, so attempts
+/// made all at once can't get past the limit while they are being verified.
+fn count_attempt(file_name: &RelativePath) -> Result<(), UnlockError> {
+    let now = Utc::now();
+    let mut failed_attempts = FAILED_ATTEMPTS
+        .lock()
+        .expect("failed attempts are not poisoned");
+    // Forget about attempts that no longer count so this doesn't grow forever.
+    failed_attempts.retain(|_, attempts| now < attempts.since + failed_attempts_window());
+
+    let attempts = failed_attempts
+        .entry(file_name.to_owned())
+        .or_insert(FailedAttempts {
+            count: 0,
+            since: now,
+        });
+    if attempts.count >= MAX_FAILED_ATTEMPTS {
+        return Err(UnlockError::TooManyAttempts);
+    }
+    attempts.count += 1;
+
+    Ok(())
+}
+
+fn forget_attempts(file_name: &RelativePath) {
+    FAILED_ATTEMPTS
+        .lock()
+        .expect("failed attempts are not poisoned")
+        .remove(file_name);
+}
+
+/// Checks `password` against the `password_hash` of the share `file_name`,
+/// handing back the cookie that unlocks the share if it is right.
+pub async fn unlock(
+    file_name: &RelativePath,
+    password_hash: &str,
+    password: String,
+    signer: &Signer,
+) -> Result<Cookie<'static>, UnlockError> {
+    count_attempt(file_name)?;
+    if !verify_password(password_hash.to_string(), password).await {
+        return Err(UnlockError::WrongPassword);
+    }
+    forget_attempts(file_name);
+
+    let token = signer.sign(&Unlock {
+        file_name: file_name.to_string(),
+        expires_at: Utc::now() + unlock_duration(),
+    });
+    Ok(Cookie::build((UNLOCK_COOKIE, token))
+        .path(format!("/file/{file_name}"))
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build())
+}
+
+/// Whether the share `file_name` has been unlocked with the cookies in `cookies`.
+pub fn is_unlocked(cookies: &CookieJar, file_name: &RelativePath, signer: &Signer) -> bool {
+    cookies.get(UNLOCK_COOKIE).is_some_and(|cookie| {
+        signer
+            .verify::<Unlock>(cookie.value())
+            .is_ok_and(|unlock| unlock.file_name == file_name.as_str())
+    })
+}
+
+/// Issued once the password to a share is entered, and required to view or
+/// download it until it expires.
+#[derive(Serialize, Deserialize, Debug)]
+struct Unlock {
+    file_name: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl Claims for Unlock {
+    const PURPOSE: &'static str = "unlock";
+
+    fn expires_at(&self) -> DateTime<Utc> { self.expires_at }
+}
@@ -0,0 +1,226 @@
+//! Shares can be moved to another expiration after they are uploaded, which
+//! changes their file name since that is where the expiration lives. A redirect
+//! is left behind so links to where they used to be keep working for as long
+//! as the share does.
+//!
+//! Redirects outlive the expiration directory they come from, so they are kept
+//! in their own top level directory as `redirects/{from}/{to}`.
+
+use axum::http::Uri;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::TryStreamExt;
+use opendal::{Metakey, Operator};
+use relative_path::{RelativePath, RelativePathBuf};
+
+use crate::{
+    blobs::{dereference_blob, reference_blob},
+    collection::{read_collection, write_collection},
+    manifest::{lock_manifest, read_manifest, ManifestError},
+    util::{
+        copy_file, get_directory_for_expiration, get_expiration_for_file_name, share_file_name,
+    },
+};
+
+/// Top level directory redirects live in, next to the expiration directories.
+pub const REDIRECTS_DIRECTORY: &str = "redirects";
+
+/// A share can be moved any number of times, but following its redirects has
+/// to stop somewhere.
+const MAX_REDIRECTS: usize = 16;
+
+fn redirect_directory(from: &RelativePath) -> RelativePathBuf {
+    RelativePath::new(REDIRECTS_DIRECTORY).join(from)
+}
+
+/// Where the share that used to be `file_name` has moved to, if it has.
+async fn read_redirect(
+    file_name: &RelativePath,
+    storage: &Operator,
+) -> Result<Option<RelativePathBuf>, opendal::Error> {
+    let directory = redirect_directory(file_name);
+    let entries = storage
+        .list_with(&format!("{directory}/"))
+        .metakey(Metakey::Mode)
+        .await?;
+
+    Ok(entries
+        .iter()
+        .find(|entry| entry.metadata().is_file())
+        .map(|entry| RelativePathBuf::from(entry.name())))
+}
+
+/// Follows the redirects of the share that used to be `file_name` to where it
+/// is now, as long as it hasn't expired there.
+pub async fn resolve_redirect(
+    file_name: &RelativePath,
+    storage: &Operator,
+) -> Result<Option<RelativePathBuf>, opendal::Error> {
+    let mut resolved = None;
+    for _ in 0..MAX_REDIRECTS {
+        let from = resolved.as_deref().unwrap_or(file_name);
+        match read_redirect(from, storage).await? {
+            Some(to) => resolved = Some(to),
+            None => break,
+        }
+    }
+
+    Ok(resolved.filter(|file_name| {
+        get_expiration_for_file_name(file_name).is_ok_and(|expiration| Utc::now() < expiration)
+    }))
+}
+
+/// Where a request for `uri` should go now that the share `from` has moved to
+/// `to`, which keeps the query since it can hold the key to the share.
+pub fn moved_uri(uri: &Uri, from: &RelativePath, to: &RelativePath) -> String {
+    let path = uri
+        .path()
+        .replacen(&format!("/file/{from}"), &format!("/file/{to}"), 1);
+    match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    }
+}
+
+/// Moves the share `file_name` to expire at `expiration_datetime` instead,
+/// returning its new file name.
+///
+/// Whatever the share holds is copied over before the old one is removed,
+/// unless it is in a blob in which case only the reference to it moves.
+pub async fn relocate_share(
+    file_name: &RelativePath,
+    expiration_datetime: DateTime<Utc>,
+    bucket_duration: TimeDelta,
+    storage: &Operator,
+) -> Result<RelativePathBuf, ManifestError> {
+    let share_directory = get_directory_for_expiration(
+        get_expiration_for_file_name(file_name).map_err(|_| ManifestError::NotFound)?,
+        bucket_duration,
+    )
+    .join(file_name);
+    let _guard = lock_manifest(&share_directory).await;
+    // It could have been removed or moved while waiting on the lock.
+    let manifest = read_manifest(&share_directory, storage).await?;
+
+    let new_file_name =
+        RelativePathBuf::from(share_file_name(expiration_datetime, file_name.extension()));
+    let new_share_directory =
+        get_directory_for_expiration(expiration_datetime, bucket_duration).join(&new_file_name);
+
+    let files = storage
+        .list_with(&format!("{share_directory}/"))
+        .recursive(true)
+        .metakey(Metakey::Mode)
+        .await?;
+    for file in files.iter().filter(|entry| entry.metadata().is_file()) {
+        let path = RelativePath::new(file.path());
+        let Ok(relative_path) = path.strip_prefix(&share_directory) else {
+            continue;
+        };
+        copy_file(path, &new_share_directory.join(relative_path), storage).await?;
+    }
+    if let Some(key) = &manifest.blob {
+        reference_blob(key, manifest.size, &new_share_directory, storage).await?;
+    }
+
+    storage
+        .write(
+            redirect_directory(file_name).join(&new_file_name).as_str(),
+            Vec::new(),
+        )
+        .await?;
+
+    storage.remove_all(&format!("{share_directory}/")).await?;
+    if let Some(key) = &manifest.blob {
+        dereference_blob(key, &share_directory, storage).await?;
+    }
+
+    tracing::info!("Moved {share_directory} to {new_share_directory}");
+
+    Ok(new_file_name)
+}
+
+/// Moves the collection `file_name` and every file in it to expire at
+/// `expiration_datetime` instead, returning its new file name.
+///
+/// Each file is moved like any other share, so links to them keep working too.
+pub async fn relocate_collection(
+    file_name: &RelativePath,
+    expiration_datetime: DateTime<Utc>,
+    bucket_duration: TimeDelta,
+    storage: &Operator,
+) -> Result<RelativePathBuf, ManifestError> {
+    let collection_directory = get_directory_for_expiration(
+        get_expiration_for_file_name(file_name).map_err(|_| ManifestError::NotFound)?,
+        bucket_duration,
+    )
+    .join(file_name);
+    let _guard = lock_manifest(&collection_directory).await;
+    // It could have been removed or moved while waiting on the lock.
+    let mut collection = read_collection(&collection_directory, storage)
+        .await?
+        .ok_or(ManifestError::NotFound)?;
+
+    for file in &mut collection.files {
+        match relocate_share(
+            RelativePath::new(&file.file_name),
+            expiration_datetime,
+            bucket_duration,
+            storage,
+        )
+        .await
+        {
+            Ok(new_file_name) => file.file_name = new_file_name.into_string(),
+            // Files that were removed on their own stay that way.
+            Err(ManifestError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let new_file_name = RelativePathBuf::from(share_file_name(expiration_datetime, None));
+    let new_collection_directory =
+        get_directory_for_expiration(expiration_datetime, bucket_duration).join(&new_file_name);
+    write_collection(&new_collection_directory, &collection, storage).await?;
+
+    storage
+        .write(
+            redirect_directory(file_name).join(&new_file_name).as_str(),
+            Vec::new(),
+        )
+        .await?;
+
+    storage
+        .remove_all(&format!("{collection_directory}/"))
+        .await?;
+
+    tracing::info!("Moved {collection_directory} to {new_collection_directory}");
+
+    Ok(new_file_name)
+}
+
+/// Removes every redirect that no longer leads to a share that hasn't expired.
+pub async fn cleanup_redirects(storage: &Operator) -> Result<(), opendal::Error> {
+    let redirects = storage
+        .lister_with(&format!("{REDIRECTS_DIRECTORY}/"))
+        .metakey(Metakey::Mode)
+        .await?
+        .try_filter(|entry| {
+            // Some services list the directory itself alongside its children.
+            let is_redirects_directory = entry.path().trim_end_matches('/') == REDIRECTS_DIRECTORY;
+            futures::future::ready(entry.metadata().is_dir() && !is_redirects_directory)
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    for redirect in redirects {
+        let from = RelativePath::new(redirect.name().trim_end_matches('/'));
+        if resolve_redirect(from, storage).await?.is_none() {
+            storage
+                .remove_all(&format!("{}/", redirect_directory(from)))
+                .await?;
+
+            tracing::info!("Removed expired redirect for '{from}'");
+        }
+    }
+
+    Ok(())
+}
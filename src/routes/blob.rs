@@ -0,0 +1,52 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use axum_thiserror::ErrorStatus;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use opendal::Operator;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    blobs::{read_blob_manifest, BlobError},
+    state::Settings,
+};
+
+#[derive(thiserror::Error, Debug, ErrorStatus)]
+pub enum GetError {
+    #[error("Expected an unpadded base64url encoded SHA-256 digest.")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidKey,
+    #[error("Blob not found.")]
+    #[status(StatusCode::NOT_FOUND)]
+    NotFound,
+    #[error(transparent)]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    Unkown(#[from] anyhow::Error),
+}
+
+/// Lets clients check whether content with the given SHA-256 digest is already
+/// stored, in which case passing its checksum when starting an upload skips
+/// uploading it again.
+pub async fn get(
+    State(storage): State<Operator>,
+    State(settings): State<Settings>,
+    Path(key): Path<String>,
+) -> Result<StatusCode, GetError> {
+    let is_sha256 = BASE64_URL_SAFE_NO_PAD
+        .decode(&key)
+        .is_ok_and(|digest| digest.len() == Sha256::output_size());
+    if !is_sha256 {
+        return Err(GetError::InvalidKey);
+    }
+
+    if !settings.deduplicate {
+        return Err(GetError::NotFound);
+    }
+
+    match read_blob_manifest(&key, &storage).await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(BlobError::NotFound) => Err(GetError::NotFound),
+        Err(err) => Err(GetError::Unkown(err.into())),
+    }
+}
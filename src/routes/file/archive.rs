@@ -0,0 +1,239 @@
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::{
+    extract::CookieJar,
+    headers::{ContentLength, ContentType},
+    TypedHeader,
+};
+use axum_thiserror::ErrorStatus;
+use opendal::Operator;
+use relative_path::{RelativePath, RelativePathBuf};
+
+use crate::{
+    archive::{archive, ArchiveEntry},
+    collection::read_collection,
+    config::Config,
+    encryption::KeyQuery,
+    manifest::{read_manifest, KeyError, Manifest, ManifestError},
+    password::is_unlocked,
+    redirects::{moved_uri, resolve_redirect},
+    session::Signer,
+    tombstone::{read_tombstone, Removal},
+    util::{
+        content_disposition, get_directory_for_expiration, get_expiration_for_file_name,
+        FileNameError,
+    },
+};
+
+#[derive(thiserror::Error, Debug, ErrorStatus)]
+pub enum ArchiveError {
+    #[error("Invalid filename is not UUID.EXT.")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidFileName,
+    #[error("UUID needs to be v7.")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidUUIDVersion,
+    #[error("UUID has an invalid timestamp.")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidUUIDTimestamp,
+    #[error("File not found.")]
+    #[status(StatusCode::NOT_FOUND)]
+    NotFound,
+    #[error("File is still being uploaded.")]
+    #[status(StatusCode::CONFLICT)]
+    UploadInProgress,
+    #[error("File is password protected, enter the password on its view page first.")]
+    #[status(StatusCode::UNAUTHORIZED)]
+    Locked,
+    #[error(
+        "Files that are end-to-end encrypted or limited to a number of downloads can't be \
+         downloaded as an archive."
+    )]
+    #[status(StatusCode::FORBIDDEN)]
+    NotArchivable,
+    #[error("File has been downloaded as many times as it was allowed to be.")]
+    #[status(StatusCode::GONE)]
+    Consumed,
+    #[error("File has been deleted by whoever shared it.")]
+    #[status(StatusCode::GONE)]
+    Deleted,
+    #[error(transparent)]
+    #[status(StatusCode::FORBIDDEN)]
+    InvalidKey(#[from] KeyError),
+    #[error(transparent)]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    Unkown(#[from] anyhow::Error),
+}
+
+impl From<Removal> for ArchiveError {
+    fn from(removal: Removal) -> Self {
+        match removal {
+            Removal::Consumed => ArchiveError::Consumed,
+            Removal::Deleted => ArchiveError::Deleted,
+        }
+    }
+}
+
+impl From<FileNameError> for ArchiveError {
+    fn from(error: FileNameError) -> Self {
+        match error {
+            FileNameError::InvalidFileName => ArchiveError::InvalidFileName,
+            FileNameError::InvalidUUIDVersion => ArchiveError::InvalidUUIDVersion,
+            FileNameError::InvalidUUIDTimestamp => ArchiveError::InvalidUUIDTimestamp,
+        }
+    }
+}
+
+/// Downloads the share `file_name` as a ZIP archive, which for a collection
+/// holds every file in it.
+pub async fn get(
+    State(storage): State<Operator>,
+    State(signer): State<Signer>,
+    State(config): State<Arc<Config>>,
+    Path(file_name): Path<RelativePathBuf>,
+    Query(key_query): Query<KeyQuery>,
+    uri: Uri,
+    cookies: CookieJar,
+) -> Result<Response, ArchiveError> {
+    let expiration_datetime = get_expiration_for_file_name(&file_name)?;
+    let directory = get_directory_for_expiration(expiration_datetime, config.bucket_duration);
+    let share_directory = directory.join(&file_name);
+
+    if chrono::Utc::now() >= expiration_datetime {
+        return redirect_if_moved(&file_name, &uri, &storage).await;
+    }
+    let (archive_name, shares) = match read_manifest(&share_directory, &storage).await {
+        Ok(manifest) => {
+            if manifest.password_hash.is_some() && !is_unlocked(&cookies, &file_name, &signer) {
+                return Err(ArchiveError::Locked);
+            }
+            let archive_name = manifest.display_name(&file_name);
+            (archive_name, vec![(file_name.clone(), None, manifest)])
+        }
+        Err(ManifestError::NotFound) => {
+            let collection = read_collection(&share_directory, &storage)
+                .await
+                .map_err(|err| ArchiveError::Unkown(err.into()))?;
+            let Some(collection) = collection else {
+                let tombstone = read_tombstone(&share_directory, &storage)
+                    .await
+                    .map_err(|err| ArchiveError::Unkown(err.into()))?;
+                return match tombstone {
+                    Some(tombstone) => Err(tombstone.removal.into()),
+                    None => redirect_if_moved(&file_name, &uri, &storage).await,
+                };
+            };
+
+            let mut shares = Vec::new();
+            for file in collection.files {
+                let file_name = RelativePathBuf::from(file.file_name);
+                match read_manifest(&directory.join(&file_name), &storage).await {
+                    Ok(manifest) => shares.push((file_name, file.path, manifest)),
+                    Err(ManifestError::NotFound) => {}
+                    Err(err) => return Err(ArchiveError::Unkown(err.into())),
+                }
+            }
+            (file_name.to_string(), shares)
+        }
+        Err(err) => return Err(ArchiveError::Unkown(err.into())),
+    };
+
+    let mut names = HashSet::new();
+    let mut entries = Vec::new();
+    for (file_name, path, manifest) in shares {
+        entries.push(archive_entry(
+            &directory.join(&file_name),
+            &file_name,
+            path,
+            &manifest,
+            key_query.key.as_deref(),
+            &mut names,
+        )?);
+    }
+
+    let (size, body) = archive(entries, &storage);
+    let content_disposition = HeaderValue::try_from(content_disposition(
+        "attachment",
+        &format!("{archive_name}.zip"),
+    ))
+    .ok()
+    .map(|value| [(header::CONTENT_DISPOSITION, value)]);
+
+    Ok((
+        TypedHeader(ContentType::from(
+            "application/zip"
+                .parse::<mime_guess::Mime>()
+                .map_err(|err| ArchiveError::Unkown(err.into()))?,
+        )),
+        TypedHeader(ContentLength(size)),
+        content_disposition,
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+/// The entry for the share in `share_directory`, under its `path` within the
+/// folder it was uploaded in if it was, and a name that isn't already one of
+/// `names`.
+fn archive_entry(
+    share_directory: &RelativePath,
+    file_name: &RelativePath,
+    path: Option<RelativePathBuf>,
+    manifest: &Manifest,
+    key: Option<&str>,
+    names: &mut HashSet<String>,
+) -> Result<ArchiveEntry, ArchiveError> {
+    if !manifest.finalized {
+        return Err(ArchiveError::UploadInProgress);
+    }
+    // Neither can be downloaded without going through the browser or counting
+    // against the limit.
+    if manifest.end_to_end_encrypted || manifest.max_downloads.is_some() {
+        return Err(ArchiveError::NotArchivable);
+    }
+    let key = manifest.check_key(key)?;
+    let parts = manifest
+        .file_parts(share_directory)
+        .ok_or(ArchiveError::NotFound)?;
+
+    let path = path.unwrap_or_else(|| RelativePathBuf::from(manifest.display_name(file_name)));
+    let mut name = path.to_string();
+    let mut copy = 1;
+    while !names.insert(name.clone()) {
+        name = match (path.file_stem(), path.extension()) {
+            (Some(stem), Some(extension)) => {
+                path.with_file_name(format!("{stem} ({copy}).{extension}"))
+            }
+            (Some(stem), None) => path.with_file_name(format!("{stem} ({copy})")),
+            _ => RelativePathBuf::from(format!("{path} ({copy})")),
+        }
+        .to_string();
+        copy += 1;
+    }
+
+    Ok(ArchiveEntry { name, parts, key })
+}
+
+/// Sends requests for a share that has moved to another expiration on to where
+/// it is now, see [`crate::redirects`].
+async fn redirect_if_moved(
+    file_name: &RelativePath,
+    uri: &Uri,
+    storage: &Operator,
+) -> Result<Response, ArchiveError> {
+    match resolve_redirect(file_name, storage)
+        .await
+        .map_err(|err| ArchiveError::Unkown(err.into()))?
+    {
+        Some(new_file_name) => {
+            Ok(Redirect::temporary(&moved_uri(uri, file_name, &new_file_name)).into_response())
+        }
+        None => Err(ArchiveError::NotFound),
+    }
+}
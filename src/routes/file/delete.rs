@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    response::Redirect,
+    Form,
+};
+use opendal::Operator;
+use relative_path::RelativePathBuf;
+use serde::Deserialize;
+
+use super::manage::{read_owned_share, ManageError, OwnedShare};
+use crate::{
+    config::Config,
+    tombstone::{remove_collection, remove_share, Removal},
+};
+
+#[derive(Deserialize)]
+pub struct DeleteForm {
+    #[serde(rename = "Token")]
+    token: String,
+}
+
+/// Deletes a share before it expires, for whoever shared it.
+pub async fn post(
+    State(storage): State<Operator>,
+    State(config): State<Arc<Config>>,
+    Path(file_name): Path<RelativePathBuf>,
+    Form(form): Form<DeleteForm>,
+) -> Result<Redirect, ManageError> {
+    let (share_directory, share) =
+        read_owned_share(&file_name, Some(&form.token), &config, &storage).await?;
+
+    match share {
+        OwnedShare::File(manifest) => {
+            remove_share(&share_directory, &manifest, Removal::Deleted, &storage).await
+        }
+        OwnedShare::Collection(collection) => {
+            remove_collection(&share_directory, &collection, Removal::Deleted, &storage).await
+        }
+    }
+    .map_err(|err| ManageError::Unkown(err.into()))?;
+
+    Ok(Redirect::to(&format!("/file/{file_name}/view")))
+}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Redirect, Response},
+    Form,
+};
+use opendal::Operator;
+use relative_path::RelativePathBuf;
+use serde::Deserialize;
+
+use super::manage::{manage_page, read_owned_share, ManageError, OwnedShare};
+use crate::{
+    config::Config,
+    manifest::ManifestError,
+    owner::manage_url,
+    redirects::{relocate_collection, relocate_share},
+};
+
+#[derive(Deserialize)]
+pub struct ExpirationForm {
+    #[serde(rename = "Token")]
+    token: String,
+    #[serde(rename = "Share for")]
+    share_for: String,
+}
+
+/// Changes when a share expires for whoever shared it, which moves it to a new
+/// link. The old one keeps working, see [`crate::redirects`].
+pub async fn post(
+    State(storage): State<Operator>,
+    State(config): State<Arc<Config>>,
+    Path(file_name): Path<RelativePathBuf>,
+    Form(form): Form<ExpirationForm>,
+) -> Result<Response, ManageError> {
+    let (_, share) = read_owned_share(&file_name, Some(&form.token), &config, &storage).await?;
+    if matches!(&share, OwnedShare::File(manifest) if !manifest.finalized) {
+        return Err(ManageError::UploadInProgress);
+    }
+
+    let expiration_datetime = match config.expiration_for(&form.share_for, chrono::Utc::now()) {
+        Ok(expiration_datetime) => expiration_datetime,
+        Err(err) => {
+            return Ok(manage_page(
+                &file_name,
+                &share,
+                Some(&form.token),
+                &config,
+                Some(&err.to_string()),
+            )
+            .into_response())
+        }
+    };
+
+    let relocated = match share {
+        OwnedShare::File(_) => {
+            relocate_share(
+                &file_name,
+                expiration_datetime,
+                config.bucket_duration,
+                &storage,
+            )
+            .await
+        }
+        OwnedShare::Collection(_) => {
+            relocate_collection(
+                &file_name,
+                expiration_datetime,
+                config.bucket_duration,
+                &storage,
+            )
+            .await
+        }
+    };
+    let new_file_name = relocated.map_err(|err| match err {
+        // It could have been removed or moved in the meantime.
+        ManifestError::NotFound => ManageError::NotFound,
+        err => ManageError::Unkown(err.into()),
+    })?;
+
+    Ok(Redirect::to(&manage_url(new_file_name.as_str(), &form.token)).into_response())
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Form,
+};
+use maud::html;
+use opendal::Operator;
+use relative_path::RelativePathBuf;
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    manifest::{finalize_manifest, ManifestError},
+    session::{Signer, TokenError, UploadSession},
+    state::Settings,
+    util::{get_directory_for_expiration, get_expiration_for_file_name, FileNameError},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PostError {
+    #[error(transparent)]
+    InvalidFileName(#[from] FileNameError),
+    #[error("Invalid upload session: {0}")]
+    InvalidToken(TokenError),
+    #[error("Upload not found.")]
+    NotFound,
+    #[error("Parts {} have not finished uploading.", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    MissingParts(Vec<usize>),
+    #[error("Unexpected error: {0}")]
+    Unkown(#[from] anyhow::Error),
+}
+
+impl IntoResponse for PostError {
+    fn into_response(self) -> axum::response::Response {
+        let status_code = match self {
+            PostError::InvalidFileName(_) => StatusCode::BAD_REQUEST,
+            PostError::InvalidToken(_) => StatusCode::FORBIDDEN,
+            PostError::NotFound => StatusCode::NOT_FOUND,
+            PostError::MissingParts(_) => StatusCode::CONFLICT,
+            PostError::Unkown(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (
+            status_code,
+            html! {
+                em {
+                    (self.to_string())
+                }
+                br;
+                br;
+            },
+        )
+            .into_response()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FinalizeForm {
+    #[serde(rename = "Token")]
+    token: String,
+}
+
+/// Marks a multipart upload as downloadable once every one of its parts landed.
+pub async fn post(
+    State(storage): State<Operator>,
+    State(signer): State<Signer>,
+    State(settings): State<Settings>,
+    State(config): State<Arc<Config>>,
+    Path(file_name): Path<RelativePathBuf>,
+    Form(form): Form<FinalizeForm>,
+) -> Result<StatusCode, PostError> {
+    let upload_session = signer
+        .verify::<UploadSession>(&form.token)
+        .map_err(PostError::InvalidToken)?;
+    if upload_session.file_name != file_name.as_str() {
+        return Err(PostError::InvalidToken(TokenError::InvalidSignature));
+    }
+
+    let expiration_datetime = get_expiration_for_file_name(&file_name)?;
+    if chrono::Utc::now() >= expiration_datetime {
+        return Err(PostError::NotFound);
+    }
+
+    let share_directory =
+        get_directory_for_expiration(expiration_datetime, config.bucket_duration).join(&file_name);
+
+    finalize_manifest(&share_directory, settings.deduplicate, &storage)
+        .await
+        .map_err(|err| match err {
+            ManifestError::NotFound => PostError::NotFound,
+            ManifestError::MissingParts(parts) => PostError::MissingParts(parts),
+            err => PostError::Unkown(err.into()),
+        })?;
+
+    tracing::info!("Finalized upload of {file_name}");
+
+    Ok(StatusCode::NO_CONTENT)
+}
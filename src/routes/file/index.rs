@@ -1,22 +1,47 @@
+use std::{io, ops::Bound, sync::Arc};
+
 use axum::{
-    extract::{Multipart, Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    body::{Body, Bytes},
+    extract::{Multipart, Path, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::{
+    extract::CookieJar,
+    headers::{AcceptRanges, ContentLength, ContentRange, ContentType, Range},
+    TypedHeader,
 };
-use axum_extra::{headers::Range, TypedHeader};
-use axum_range::{KnownSize, Ranged};
 use axum_thiserror::ErrorStatus;
-use chrono::TimeZone;
-use futures::TryStreamExt;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::{Stream, TryStreamExt};
 use maud::{html, Markup};
+use mime_guess::{mime, Mime};
 use opendal::Operator;
-use relative_path::RelativePathBuf;
-use uuid::Uuid;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
-use crate::util::{
-    get_and_validate_multipart_field, get_directory_for_expiration, write_file, MultipartError,
+use crate::{
+    compression::accepts_encoding,
+    config::Config,
+    downloads::{claim_download, read_downloads},
+    encryption::KeyQuery,
+    manifest::{read_manifest, record_part, KeyError, Manifest, ManifestError},
+    parts::{part_path, parts_size, read_parts_range, read_parts_stored},
+    password::is_unlocked,
+    redirects::{moved_uri, resolve_redirect},
+    session::{Signer, TokenError, UploadSession},
+    state::Settings,
+    tombstone::{read_tombstone, remove_share_after, Removal},
+    util::{
+        content_disposition, get_and_validate_multipart_field, get_directory_for_expiration,
+        get_expiration_for_file_name, get_next_multipart_field, write_file, FileNameError,
+        MultipartError, WriteFileError, WriteFileOptions,
+    },
 };
 
+const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+
 #[derive(thiserror::Error, Debug, ErrorStatus)]
 pub enum GetError {
     #[error("Invalid filename is not UUID.EXT.")]
@@ -31,67 +56,352 @@ pub enum GetError {
     #[error("File not found.")]
     #[status(StatusCode::NOT_FOUND)]
     NotFound,
+    #[error("File is still being uploaded.")]
+    #[status(StatusCode::CONFLICT)]
+    UploadInProgress,
+    #[error("File is password protected, enter the password on its view page first.")]
+    #[status(StatusCode::UNAUTHORIZED)]
+    Locked,
+    #[error("File has been downloaded as many times as it was allowed to be.")]
+    #[status(StatusCode::GONE)]
+    Consumed,
+    #[error("File has been deleted by whoever shared it.")]
+    #[status(StatusCode::GONE)]
+    Deleted,
+    #[error(transparent)]
+    #[status(StatusCode::FORBIDDEN)]
+    InvalidKey(#[from] KeyError),
     #[error(transparent)]
     #[status(StatusCode::INTERNAL_SERVER_ERROR)]
     Unkown(#[from] anyhow::Error),
 }
 
+impl From<Removal> for GetError {
+    fn from(removal: Removal) -> Self {
+        match removal {
+            Removal::Consumed => GetError::Consumed,
+            Removal::Deleted => GetError::Deleted,
+        }
+    }
+}
+
+impl From<FileNameError> for GetError {
+    fn from(error: FileNameError) -> Self {
+        match error {
+            FileNameError::InvalidFileName => GetError::InvalidFileName,
+            FileNameError::InvalidUUIDVersion => GetError::InvalidUUIDVersion,
+            FileNameError::InvalidUUIDTimestamp => GetError::InvalidUUIDTimestamp,
+        }
+    }
+}
+
+/// Whether a file should be shown in the browser or saved, which is shown by
+/// default so it can be embedded.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposition {
+    #[default]
+    Inline,
+    Attachment,
+}
+
+impl Disposition {
+    fn as_str(self) -> &'static str {
+        match self {
+            Disposition::Inline => "inline",
+            Disposition::Attachment => "attachment",
+        }
+    }
+}
+
+/// The `?disposition=` of a link to download a file.
+#[derive(Deserialize)]
+pub struct DispositionQuery {
+    #[serde(default)]
+    pub disposition: Disposition,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn get(
     State(storage): State<Operator>,
+    State(signer): State<Signer>,
+    State(config): State<Arc<Config>>,
+    method: Method,
     range: Option<TypedHeader<Range>>,
+    headers: HeaderMap,
     Path(file_name): Path<RelativePathBuf>,
-) -> Result<impl IntoResponse, GetError> {
-    let uuid = file_name
-        .file_stem()
-        .and_then(|stem| Uuid::try_parse(stem).ok())
-        .ok_or(GetError::InvalidFileName)?;
-
-    let expiration_timestamp = uuid.get_timestamp().ok_or(GetError::InvalidUUIDVersion)?;
-    let (seconds, subsec_nanos) = expiration_timestamp.to_unix();
-    let expiration_datetime = match chrono::Utc.timestamp_opt(seconds as i64, subsec_nanos) {
-        chrono::offset::LocalResult::Single(datetime) => Ok(datetime),
-        _ => Err(GetError::InvalidUUIDTimestamp),
-    }?;
+    Query(key_query): Query<KeyQuery>,
+    Query(DispositionQuery { disposition }): Query<DispositionQuery>,
+    uri: Uri,
+    cookies: CookieJar,
+) -> Result<Response, GetError> {
+    let expiration_datetime = get_expiration_for_file_name(&file_name)?;
 
     if chrono::Utc::now() >= expiration_datetime {
-        Err(GetError::NotFound)
+        redirect_if_moved(&file_name, &uri, &storage).await
     } else {
-        let directory = get_directory_for_expiration(expiration_datetime);
-        // TODO: Here we need to read the various parts and reconstruct the file
-        let file_path = directory.join(&file_name).join("0"); // TEMP
+        let share_directory =
+            get_directory_for_expiration(expiration_datetime, config.bucket_duration)
+                .join(&file_name);
+        let manifest = match read_manifest(&share_directory, &storage).await {
+            Ok(manifest) => manifest,
+            // It could have been removed before it expired.
+            Err(ManifestError::NotFound) => {
+                let tombstone = read_tombstone(&share_directory, &storage)
+                    .await
+                    .map_err(|err| GetError::Unkown(err.into()))?;
+                return match tombstone {
+                    Some(tombstone) => Err(tombstone.removal.into()),
+                    None => redirect_if_moved(&file_name, &uri, &storage).await,
+                };
+            }
+            Err(err) => return Err(GetError::Unkown(err.into())),
+        };
+        if !manifest.finalized {
+            return Err(GetError::UploadInProgress);
+        }
+        if manifest.password_hash.is_some() && !is_unlocked(&cookies, &file_name, &signer) {
+            return Err(GetError::Locked);
+        }
+        let key = manifest.check_key(key_query.key.as_deref())?;
+        let parts = manifest
+            .file_parts(&share_directory)
+            .ok_or(GetError::NotFound)?;
+        let size = parts_size(&parts);
+        // Every download of a file with a limit counts, so it can't be pieced
+        // together from ranges that each stop short of the end instead.
+        let range = range.filter(|_| manifest.max_downloads.is_none());
+        // End-to-end encrypted files are opaque to us, and are served as such.
+        let content_type = if manifest.end_to_end_encrypted {
+            Some(mime::APPLICATION_OCTET_STREAM)
+        } else {
+            manifest
+                .media_type(&file_name)
+                .map(|mime| match disposition {
+                    Disposition::Inline => servable_media_type(mime),
+                    Disposition::Attachment => mime,
+                })
+        }
+        .map(|mime| {
+            (
+                TypedHeader(ContentType::from(mime)),
+                [(header::X_CONTENT_TYPE_OPTIONS, "nosniff")],
+            )
+        });
+        // Saved under the name it was uploaded with, rather than the one it is shared under.
+        let content_disposition = HeaderValue::try_from(content_disposition(
+            disposition.as_str(),
+            &manifest.display_name(&file_name),
+        ))
+        .ok()
+        .map(|value| [(header::CONTENT_DISPOSITION, value)]);
 
-        let bytes = storage
-            .stat(file_path.as_str())
-            .await
-            .map_err(|err| GetError::Unkown(err.into()))?
-            .content_length();
-
-        // TODO: @ the moment this will read the entire file and only return a portion of it,
-        // if I wanted to be efficient I could parse the range myself rather than using the
-        // Ranged library. We will see if that's worth though.
-        let reader = storage
-            .reader(file_path.as_str())
+        // Compressed parts can be passed straight through when the client can
+        // decompress them itself, as long as it wants the whole file and they
+        // aren't encrypted.
+        let codec = parts.first().and_then(|part| part.codec).filter(|&codec| {
+            parts
+                .iter()
+                .all(|part| part.codec == Some(codec) && !part.encrypted)
+        });
+        let vary = codec.map(|_| [(header::VARY, header::ACCEPT_ENCODING.as_str())]);
+        if let Some(codec) = codec
+            .filter(|codec| range.is_none() && accepts_encoding(&headers, codec.content_encoding()))
+        {
+            let stored_size = parts.iter().map(|part| part.stored_size).sum();
+            let body = count_download(
+                read_parts_stored(&parts, &storage),
+                method != Method::HEAD,
+                &share_directory,
+                &manifest,
+                &storage,
+            )
+            .await?;
+            return Ok((
+                [(header::CONTENT_ENCODING, codec.content_encoding())],
+                vary,
+                content_type,
+                content_disposition,
+                TypedHeader(ContentLength(stored_size)),
+                TypedHeader(AcceptRanges::bytes()),
+                body,
+            )
+                .into_response());
+        }
+
+        // We resolve the range ourselves so only the parts (and the slices of those
+        // parts) that are actually requested ever get read from storage.
+        let (status_code, content_range, window) = match range {
+            None => (StatusCode::OK, None, 0..size),
+            Some(TypedHeader(range)) => match resolve_range(&range, size) {
+                Some(window) => {
+                    let content_range = ContentRange::bytes(window.clone(), size)
+                        .map_err(|err| GetError::Unkown(anyhow::anyhow!("{err:?}")))?;
+                    (StatusCode::PARTIAL_CONTENT, Some(content_range), window)
+                }
+                None => {
+                    return Ok((
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        TypedHeader(ContentRange::unsatisfied_bytes(size)),
+                    )
+                        .into_response())
+                }
+            },
+        };
+
+        let content_length = ContentLength(window.end - window.start);
+        let body = count_download(
+            read_parts_range(&parts, window, key.as_ref(), &storage),
+            method != Method::HEAD,
+            &share_directory,
+            &manifest,
+            &storage,
+        )
+        .await?;
+
+        // The digest is of the whole file regardless of the range (RFC 9530).
+        let repr_digest = manifest
+            .checksum
+            .map(|checksum| [(REPR_DIGEST, format!("sha-256=:{checksum}:"))]);
+
+        Ok((
+            status_code,
+            content_range.map(TypedHeader),
+            TypedHeader(content_length),
+            content_type,
+            TypedHeader(AcceptRanges::bytes()),
+            repr_digest,
+            vary,
+            content_disposition,
+            body,
+        )
+            .into_response())
+    }
+}
+
+/// Files are served from the same origin as everything else, so types browsers
+/// would run scripts in are served as plain text instead when shown inline.
+fn servable_media_type(mime: Mime) -> Mime {
+    let is_active = matches!(
+        (mime.type_(), mime.subtype().as_str()),
+        (mime::TEXT, "html" | "xml" | "javascript")
+            | (mime::APPLICATION, "xhtml" | "xml" | "javascript")
+            | (mime::IMAGE, "svg")
+    ) || mime.suffix() == Some(mime::XML);
+    if is_active {
+        mime::TEXT_PLAIN_UTF_8
+    } else {
+        mime
+    }
+}
+
+/// Sends requests for a share that has moved to another expiration on to where
+/// it is now, see [`crate::redirects`].
+async fn redirect_if_moved(
+    file_name: &RelativePath,
+    uri: &Uri,
+    storage: &Operator,
+) -> Result<Response, GetError> {
+    match resolve_redirect(file_name, storage)
+        .await
+        .map_err(|err| GetError::Unkown(err.into()))?
+    {
+        // Temporary so ranges and HEAD requests are repeated as they were.
+        Some(new_file_name) => {
+            Ok(Redirect::temporary(&moved_uri(uri, file_name, &new_file_name)).into_response())
+        }
+        None => Err(GetError::NotFound),
+    }
+}
+
+/// Counts the download against the file's limit if it has one and the download
+/// `counts`, removing the file once `body` has been sent if that was its last.
+///
+/// Files with a limit are always sent whole, see [`get`].
+async fn count_download<S>(
+    body: S,
+    counts: bool,
+    share_directory: &RelativePath,
+    manifest: &Manifest,
+    storage: &Operator,
+) -> Result<Body, GetError>
+where
+    S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+{
+    let Some(max_downloads) = manifest.max_downloads else {
+        return Ok(Body::from_stream(body));
+    };
+
+    if !counts {
+        let downloads = read_downloads(share_directory, storage)
             .await
             .map_err(|err| GetError::Unkown(err.into()))?;
+        return if downloads < max_downloads {
+            Ok(Body::from_stream(body))
+        } else {
+            Err(GetError::Consumed)
+        };
+    }
 
-        let body = KnownSize::sized(reader, bytes);
-        let range = range.map(|TypedHeader(range)| range);
-        Ok(Ranged::new(range, body))
+    match claim_download(share_directory, max_downloads, storage)
+        .await
+        .map_err(|err| GetError::Unkown(err.into()))?
+    {
+        None => Err(GetError::Consumed),
+        Some(0) => Ok(Body::from_stream(remove_share_after(
+            body,
+            share_directory.to_owned(),
+            manifest.clone(),
+            Removal::Consumed,
+            storage.clone(),
+        ))),
+        Some(_) => Ok(Body::from_stream(body)),
     }
 }
 
+/// Resolves the first satisfiable range of a `Range` header against a file that
+/// is `size` bytes long.
+fn resolve_range(range: &Range, size: u64) -> Option<std::ops::Range<u64>> {
+    let (start, end) = range.satisfiable_ranges(size).next()?;
+    let start = match start {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    // HTTP ranges are inclusive, and ending past the file just means "until the end".
+    let end = match end {
+        Bound::Included(end) => end.saturating_add(1).min(size),
+        Bound::Excluded(end) => end.min(size),
+        Bound::Unbounded => size,
+    };
+
+    (start < end).then_some(start..end)
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum PostError {
     #[error("'{0}' is required!")]
     MissingField(&'static str),
+    #[error("Invalid upload session: {0}")]
+    InvalidToken(TokenError),
+    #[error("Part is larger than the allowed {0} bytes.")]
+    PartTooLarge(u64),
     #[error("Invalid part field.")]
     InvalidPartField,
+    #[error("Invalid checksum field, expected a base64 encoded SHA-256 digest.")]
+    InvalidChecksumField,
+    #[error("Part was corrupted during upload.")]
+    ChecksumMismatch,
     #[error("Missing file name.")]
     InvalidFileName,
     #[error("UUID needs to be v7.")]
     InvalidUUIDVersion,
     #[error("UUID has an invalid timestamp.")]
     InvalidUUIDTimestamp,
+    #[error("Upload not found.")]
+    NotFound,
+    #[error("Upload has already been finalized.")]
+    AlreadyFinalized,
     #[error("Unexpected error: {0}")]
     Unkown(#[from] anyhow::Error),
 }
@@ -100,6 +410,10 @@ impl IntoResponse for PostError {
     fn into_response(self) -> axum::response::Response {
         let (status_code, include_retry_button) = match self {
             PostError::Unkown(_) => (StatusCode::INTERNAL_SERVER_ERROR, true),
+            PostError::ChecksumMismatch => (StatusCode::UNPROCESSABLE_ENTITY, true),
+            PostError::InvalidToken(_) => (StatusCode::FORBIDDEN, false),
+            PostError::PartTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, false),
+            PostError::AlreadyFinalized => (StatusCode::CONFLICT, false),
             _ => (StatusCode::BAD_REQUEST, false),
         };
 
@@ -121,6 +435,16 @@ impl IntoResponse for PostError {
     }
 }
 
+impl From<FileNameError> for PostError {
+    fn from(error: FileNameError) -> Self {
+        match error {
+            FileNameError::InvalidFileName => PostError::InvalidFileName,
+            FileNameError::InvalidUUIDVersion => PostError::InvalidUUIDVersion,
+            FileNameError::InvalidUUIDTimestamp => PostError::InvalidUUIDTimestamp,
+        }
+    }
+}
+
 impl From<MultipartError> for PostError {
     fn from(error: MultipartError) -> Self {
         match error {
@@ -132,9 +456,24 @@ impl From<MultipartError> for PostError {
 
 pub async fn post(
     State(storage): State<Operator>,
+    State(signer): State<Signer>,
+    State(settings): State<Settings>,
+    State(config): State<Arc<Config>>,
     Path(file_name): Path<RelativePathBuf>,
     mut multipart: Multipart,
 ) -> Result<Markup, PostError> {
+    let token = get_and_validate_multipart_field("Token", &mut multipart)
+        .await?
+        .text()
+        .await
+        .map_err(|_| PostError::InvalidToken(TokenError::Malformed))?;
+    let upload_session = signer
+        .verify::<UploadSession>(&token)
+        .map_err(PostError::InvalidToken)?;
+    if upload_session.file_name != file_name.as_str() {
+        return Err(PostError::InvalidToken(TokenError::InvalidSignature));
+    }
+
     let part_field = get_and_validate_multipart_field("Part", &mut multipart).await?;
     let part = part_field
         .text()
@@ -149,37 +488,215 @@ pub async fn post(
     //     Err(PostError::Unkown(anyhow::anyhow!("DEBUG")))?;
     // }
 
-    let uuid = file_name
-        .file_stem()
-        .and_then(|stem| Uuid::try_parse(stem).ok())
-        .ok_or(PostError::InvalidFileName)?;
+    let expiration_datetime = get_expiration_for_file_name(&file_name)?;
 
-    let expiration_timestamp = uuid.get_timestamp().ok_or(PostError::InvalidUUIDVersion)?;
-    let (seconds, subsec_nanos) = expiration_timestamp.to_unix();
-    let expiration_datetime = match chrono::Utc.timestamp_opt(seconds as i64, subsec_nanos) {
-        chrono::offset::LocalResult::Single(datetime) => Ok(datetime),
-        _ => Err(PostError::InvalidUUIDTimestamp),
-    }?;
+    let share_directory =
+        get_directory_for_expiration(expiration_datetime, config.bucket_duration).join(&file_name);
+    let manifest = read_manifest(&share_directory, &storage)
+        .await
+        .map_err(|err| match err {
+            ManifestError::NotFound => PostError::NotFound,
+            err => PostError::Unkown(err.into()),
+        })?;
+    if manifest.finalized {
+        return Err(PostError::AlreadyFinalized);
+    }
+    // Tokens are only ever signed with as many parts as the manifest expects.
+    if upload_session.parts != manifest.parts.len() {
+        return Err(PostError::InvalidToken(TokenError::InvalidSignature));
+    }
+    if part >= upload_session.parts {
+        return Err(PostError::InvalidPartField);
+    }
 
-    // TODO stat this directory???
+    // The checksum is optional, so the next field is either it or the file itself.
+    let mut field = get_next_multipart_field(&mut multipart)
+        .await?
+        .ok_or(PostError::MissingField("File"))?;
+    let checksum = if field.name() == Some("Checksum") {
+        let checksum = field
+            .text()
+            .await
+            .map_err(|_| PostError::InvalidChecksumField)?;
+        field = get_and_validate_multipart_field("File", &mut multipart).await?;
+        Some(checksum).filter(|checksum| !checksum.is_empty())
+    } else {
+        None
+    };
 
-    let file_field = get_and_validate_multipart_field("File", &mut multipart).await?;
-    let body_with_io_error = file_field
-        .map_err(|err| opendal::Error::new(opendal::ErrorKind::Unexpected, &err.body_text()));
+    if field.name() != Some("File") {
+        return Err(PostError::MissingField("File"));
+    }
+    if let Some(checksum) = &checksum {
+        let is_sha256 = BASE64_STANDARD
+            .decode(checksum)
+            .is_ok_and(|digest| digest.len() == Sha256::output_size());
+        if !is_sha256 {
+            return Err(PostError::InvalidChecksumField);
+        }
+    }
 
-    let directory = get_directory_for_expiration(expiration_datetime);
-    let part_path = directory.join(file_name.as_str()).join(part.to_string());
+    let body_with_io_error =
+        field.map_err(|err| opendal::Error::new(opendal::ErrorKind::Unexpected, &err.body_text()));
 
     tracing::info!("Writing part {}", part);
 
-    write_file(&part_path, body_with_io_error, &storage)
+    let written_part = write_file(
+        &part_path(&share_directory, part),
+        body_with_io_error,
+        WriteFileOptions {
+            expected_checksum: checksum.as_deref(),
+            max_size: Some(upload_session.max_part_size()),
+            // Encrypted bytes don't compress.
+            codec: settings
+                .codec_for(&file_name)
+                .filter(|_| !manifest.end_to_end_encrypted),
+            key: upload_session.key.as_ref(),
+            buffer_size: Some(config.write_buffer_size),
+        },
+        &storage,
+    )
+    .await
+    .map_err(|err| match err {
+        WriteFileError::ChecksumMismatch { .. } => PostError::ChecksumMismatch,
+        WriteFileError::TooLarge(max_size) => PostError::PartTooLarge(max_size),
+        err => PostError::Unkown(err.into()),
+    })?;
+    record_part(&share_directory, part, written_part, &storage)
         .await
-        .map_err(|err| PostError::Unkown(err.into()))?;
+        .map_err(|err| match err {
+            ManifestError::NotFound => PostError::NotFound,
+            ManifestError::AlreadyFinalized => PostError::AlreadyFinalized,
+            err => PostError::Unkown(err.into()),
+        })?;
 
     tracing::info!("Finished upload with part {}", part);
 
-    // TODO DEBUG NO PART 0 but FOLDER!!!
-    // Also error responses!
-
     Ok(html! {})
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use chrono::{TimeDelta, Utc};
+    use opendal::services;
+
+    use super::*;
+    use crate::{
+        manifest::{write_manifest, PartManifest},
+        util::share_file_name,
+    };
+
+    const CONTENT: &[u8] = b"Hello, world!";
+
+    /// Shares [`CONTENT`] in a single part, returning the file name it is
+    /// shared under.
+    async fn share(max_downloads: Option<u64>, config: &Config, storage: &Operator) -> String {
+        let expiration = Utc::now() + TimeDelta::hours(1);
+        let file_name = share_file_name(expiration, Some("txt"));
+        let share_directory =
+            get_directory_for_expiration(expiration, config.bucket_duration).join(&file_name);
+
+        storage
+            .write(part_path(&share_directory, 0).as_str(), CONTENT)
+            .await
+            .unwrap();
+        let manifest = Manifest {
+            parts: vec![Some(PartManifest {
+                size: CONTENT.len() as u64,
+                checksum: None,
+                codec: None,
+                encrypted: false,
+                stored_size: None,
+            })],
+            finalized: true,
+            max_downloads,
+            ..Manifest::new("hello.txt".to_string(), CONTENT.len() as u64, 1)
+        };
+        write_manifest(&share_directory, &manifest, storage)
+            .await
+            .unwrap();
+
+        file_name
+    }
+
+    async fn download(
+        file_name: &str,
+        range: Option<Range>,
+        config: &Arc<Config>,
+        storage: &Operator,
+    ) -> Result<(StatusCode, Bytes), GetError> {
+        let response = get(
+            State(storage.clone()),
+            State(Signer::random()),
+            State(config.clone()),
+            Method::GET,
+            range.map(TypedHeader),
+            HeaderMap::new(),
+            Path(RelativePathBuf::from(file_name)),
+            Query(KeyQuery { key: None }),
+            Query(DispositionQuery {
+                disposition: Disposition::Attachment,
+            }),
+            format!("/file/{file_name}").parse().unwrap(),
+            CookieJar::new(),
+        )
+        .await?;
+        let status = response.status();
+        // Shares are only removed once their last download has been sent.
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        Ok((status, body))
+    }
+
+    fn storage() -> Operator { Operator::new(services::Memory::default()).unwrap().finish() }
+
+    #[tokio::test]
+    async fn ranges_are_served_without_a_limit() {
+        let config = Arc::new(Config::default());
+        let storage = storage();
+        let file_name = share(None, &config, &storage).await;
+
+        let range = Range::bytes(0..5).unwrap();
+        let (status, body) = download(&file_name, Some(range), &config, &storage)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, CONTENT[..5]);
+    }
+
+    #[tokio::test]
+    async fn partial_ranges_use_up_a_download() {
+        let config = Arc::new(Config::default());
+        let storage = storage();
+        let file_name = share(Some(1), &config, &storage).await;
+
+        let range = Range::bytes(0..5).unwrap();
+        let (status, body) = download(&file_name, Some(range), &config, &storage)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, CONTENT);
+
+        let range = Range::bytes(5..).unwrap();
+        assert!(matches!(
+            download(&file_name, Some(range), &config, &storage).await,
+            Err(GetError::Consumed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn every_download_counts() {
+        let config = Arc::new(Config::default());
+        let storage = storage();
+        let file_name = share(Some(2), &config, &storage).await;
+
+        for _ in 0..2 {
+            let (status, _) = download(&file_name, None, &config, &storage).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert!(matches!(
+            download(&file_name, None, &config, &storage).await,
+            Err(GetError::Consumed)
+        ));
+    }
+}
@@ -0,0 +1,234 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+};
+use chrono::TimeDelta;
+use maud::{html, Markup, Render};
+use opendal::Operator;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::Deserialize;
+
+use crate::{
+    collection::{read_collection, Collection},
+    components::{error_page::error_page, page::page, share_for::share_for_field},
+    config::{format_duration, Config},
+    manifest::{read_manifest, Manifest, ManifestError},
+    owner::check_owner_token,
+    redirects::{moved_uri, resolve_redirect},
+    util::{
+        format_size, get_directory_for_expiration, get_expiration_for_file_name, FileNameError,
+    },
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ManageError {
+    #[error("Invalid filename is not UUID.EXT.")]
+    InvalidFileName,
+    #[error("UUID needs to be v7.")]
+    InvalidUUIDVersion,
+    #[error("UUID has an invalid timestamp.")]
+    InvalidUUIDTimestamp,
+    #[error("File not found.")]
+    NotFound,
+    #[error("Invalid owner token.")]
+    InvalidToken,
+    #[error("File is still being uploaded.")]
+    UploadInProgress,
+    #[error(transparent)]
+    Unkown(#[from] anyhow::Error),
+}
+
+impl IntoResponse for ManageError {
+    fn into_response(self) -> Response {
+        match self {
+            ManageError::InvalidFileName
+            | ManageError::InvalidUUIDTimestamp
+            | ManageError::InvalidUUIDVersion
+            | ManageError::NotFound => error_page(
+                StatusCode::NOT_FOUND,
+                "This file has either expired, been removed, or never even existed in the first place.",
+            ),
+            ManageError::InvalidToken => error_page(
+                StatusCode::FORBIDDEN,
+                "Only whoever shared this file can manage it, make sure you have the whole link you were given.",
+            ),
+            ManageError::UploadInProgress => error_page(
+                StatusCode::CONFLICT,
+                "This file is still being uploaded, check back once the upload finishes.",
+            ),
+            ManageError::Unkown(err) => {
+                tracing::error!("Unkown error encountered when managing a file: {err}");
+                error_page(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "An unknown error occured when trying to manage file.",
+                )
+            }
+        }
+        .into_response()
+    }
+}
+
+impl From<FileNameError> for ManageError {
+    fn from(error: FileNameError) -> Self {
+        match error {
+            FileNameError::InvalidFileName => ManageError::InvalidFileName,
+            FileNameError::InvalidUUIDVersion => ManageError::InvalidUUIDVersion,
+            FileNameError::InvalidUUIDTimestamp => ManageError::InvalidUUIDTimestamp,
+        }
+    }
+}
+
+/// The `?token=` of a link to manage a share.
+#[derive(Deserialize)]
+pub struct OwnerTokenQuery {
+    pub token: Option<String>,
+}
+
+/// A share that can be managed, which is either a single file or a collection
+/// of them.
+pub enum OwnedShare {
+    File(Manifest),
+    Collection(Collection),
+}
+
+impl OwnedShare {
+    fn owner_token_digest(&self) -> Option<&str> {
+        match self {
+            OwnedShare::File(manifest) => manifest.owner_token_digest.as_deref(),
+            OwnedShare::Collection(collection) => collection.owner_token_digest.as_deref(),
+        }
+    }
+}
+
+/// Reads the manifest or collection of the share `file_name`, as long as
+/// `token` is the owner token for it, returning where the share lives along
+/// with it.
+pub async fn read_owned_share(
+    file_name: &RelativePath,
+    token: Option<&str>,
+    config: &Config,
+    storage: &Operator,
+) -> Result<(RelativePathBuf, OwnedShare), ManageError> {
+    let expiration_datetime = get_expiration_for_file_name(file_name)?;
+    if chrono::Utc::now() >= expiration_datetime {
+        return Err(ManageError::NotFound);
+    }
+
+    let share_directory =
+        get_directory_for_expiration(expiration_datetime, config.bucket_duration).join(file_name);
+    let share = match read_manifest(&share_directory, storage).await {
+        Ok(manifest) => OwnedShare::File(manifest),
+        Err(ManifestError::NotFound) => read_collection(&share_directory, storage)
+            .await
+            .map_err(|err| ManageError::Unkown(err.into()))?
+            .map(OwnedShare::Collection)
+            .ok_or(ManageError::NotFound)?,
+        Err(err) => return Err(ManageError::Unkown(err.into())),
+    };
+
+    let is_owner = token.is_some_and(|token| check_owner_token(share.owner_token_digest(), token));
+    if !is_owner {
+        return Err(ManageError::InvalidToken);
+    }
+
+    Ok((share_directory, share))
+}
+
+pub async fn get(
+    State(storage): State<Operator>,
+    State(config): State<Arc<Config>>,
+    Path(file_name): Path<RelativePathBuf>,
+    Query(token_query): Query<OwnerTokenQuery>,
+    uri: Uri,
+) -> Result<Response, ManageError> {
+    let token = token_query.token.as_deref();
+    let share = match read_owned_share(&file_name, token, &config, &storage).await {
+        Ok((_, share)) => share,
+        // It could have been moved to another expiration.
+        Err(ManageError::NotFound) => {
+            return match resolve_redirect(&file_name, &storage)
+                .await
+                .map_err(|err| ManageError::Unkown(err.into()))?
+            {
+                Some(new_file_name) => {
+                    Ok(Redirect::to(&moved_uri(&uri, &file_name, &new_file_name)).into_response())
+                }
+                None => Err(ManageError::NotFound),
+            };
+        }
+        Err(err) => return Err(err),
+    };
+
+    Ok(manage_page(&file_name, &share, token, &config, None).into_response())
+}
+
+/// Shows whoever shared `file_name` what they can do with it, along with
+/// `error` if what they last tried didn't work out.
+pub fn manage_page(
+    file_name: &RelativePath,
+    share: &OwnedShare,
+    token: Option<&str>,
+    config: &Config,
+    error: Option<&dyn Render>,
+) -> Markup {
+    let expiration_datetime = get_expiration_for_file_name(file_name).unwrap_or_default();
+    let expires_in = format_duration(TimeDelta::seconds(
+        (expiration_datetime - chrono::Utc::now()).num_seconds(),
+    ));
+
+    page(
+        html! {
+            fieldset {
+                @match share {
+                    OwnedShare::File(manifest) => {
+                        h2 { "Managing " code { (manifest.display_name(file_name)) }}
+                        p {
+                            "This file (" (format_size(manifest.size)) ") expires in " (expires_in)
+                            ", at " time { (expiration_datetime.to_rfc3339()) } "."
+                        }
+                        p {
+                            "Links to it that you've already shared keep working when you change when "
+                            "it expires, though this link to manage it changes."
+                        }
+                    }
+                    OwnedShare::Collection(collection) => {
+                        h2 { "Managing " (collection.files.len()) " files" }
+                        p {
+                            "These files expire in " (expires_in)
+                            ", at " time { (expiration_datetime.to_rfc3339()) } "."
+                        }
+                        p {
+                            "Links to them that you've already shared keep working when you change when "
+                            "they expire, though this link to manage them changes."
+                        }
+                    }
+                }
+                @if let Some(error) = error {
+                    em id="error" { (error) }
+                    br;br;
+                }
+                // Every file in a collection is finalized before the collection is shared.
+                @if !matches!(share, OwnedShare::File(manifest) if !manifest.finalized) {
+                    form method="post" action=(format!("/file/{file_name}/expiration")) {
+                        input type="hidden" name="Token" value=[token];
+                        (share_for_field(config))
+                        br;br;
+                        input type="submit" value="Change expiration" data-loading-disable data-loading-aria-busy;
+                    }
+                }
+                form method="post" action=(format!("/file/{file_name}/delete"))
+                hx-confirm=(match share {
+                    OwnedShare::File(_) => "Delete this file for everyone right away?",
+                    OwnedShare::Collection(_) => "Delete these files for everyone right away?",
+                }) {
+                    input type="hidden" name="Token" value=[token];
+                    input type="submit" value="Delete now" data-loading-disable data-loading-aria-busy;
+                }
+            }
+        },
+        false,
+    )
+}
@@ -1,2 +1,8 @@
+pub mod archive;
+pub mod delete;
+pub mod expiration;
+pub mod finalize;
 pub mod index;
+pub mod manage;
+pub mod unlock;
 pub mod view;
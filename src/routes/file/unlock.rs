@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::Uri,
+    response::{IntoResponse, Redirect, Response},
+    Form,
+};
+use axum_extra::extract::CookieJar;
+use maud::{html, Markup};
+use opendal::Operator;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::Deserialize;
+
+use super::view::GetError;
+use crate::{
+    components::page::page,
+    config::Config,
+    manifest::{read_manifest, ManifestError},
+    password::{unlock, UnlockError},
+    session::Signer,
+    util::{get_directory_for_expiration, get_expiration_for_file_name},
+};
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    #[serde(rename = "Password")]
+    password: String,
+}
+
+/// Asks for the password to `file_name`, which is unlocked back on its view
+/// page with the same `query` (which can hold the key to the share).
+pub fn password_prompt(
+    file_name: &RelativePath,
+    query: Option<&str>,
+    error: Option<&UnlockError>,
+) -> Markup {
+    let action = match query {
+        Some(query) => format!("/file/{file_name}/unlock?{query}"),
+        None => format!("/file/{file_name}/unlock"),
+    };
+
+    html! {
+        form method="post" action=(action) {
+            label for="password" { "This file is password protected: " }
+            input id="password" type="password" name="Password" autocomplete="current-password"
+            required autofocus;
+            " "
+            input type="submit" value="Unlock" data-loading-disable data-loading-aria-busy;
+            @if let Some(error) = error {
+                br;br;
+                em id="error" { (error) }
+            }
+        }
+    }
+}
+
+pub async fn post(
+    State(storage): State<Operator>,
+    State(signer): State<Signer>,
+    State(config): State<Arc<Config>>,
+    Path(file_name): Path<RelativePathBuf>,
+    uri: Uri,
+    cookies: CookieJar,
+    Form(form): Form<UnlockForm>,
+) -> Result<Response, GetError> {
+    let view_url = match uri.query() {
+        Some(query) => format!("/file/{file_name}/view?{query}"),
+        None => format!("/file/{file_name}/view"),
+    };
+
+    let expiration_datetime = get_expiration_for_file_name(&file_name)?;
+    let share_directory =
+        get_directory_for_expiration(expiration_datetime, config.bucket_duration).join(&file_name);
+    let manifest = match read_manifest(&share_directory, &storage).await {
+        Ok(manifest) => manifest,
+        // The view page explains where it went.
+        Err(ManifestError::NotFound) => return Ok(Redirect::to(&view_url).into_response()),
+        Err(err) => return Err(GetError::Unkown(err.into())),
+    };
+    let Some(password_hash) = &manifest.password_hash else {
+        return Ok(Redirect::to(&view_url).into_response());
+    };
+
+    match unlock(&file_name, password_hash, form.password, &signer).await {
+        Ok(cookie) => Ok((cookies.add(cookie), Redirect::to(&view_url)).into_response()),
+        Err(err) => Ok(page(
+            html! {
+                fieldset {
+                    h2 { "Viewing " code { (manifest.display_name(&file_name)) }}
+                    (password_prompt(&file_name, uri.query(), Some(&err)))
+                }
+            },
+            false,
+        )
+        .into_response()),
+    }
+}
@@ -1,23 +1,43 @@
-use std::str::from_utf8;
+use std::{collections::BTreeMap, str::from_utf8, sync::Arc};
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    extract::{Path, Query, State},
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
 };
-use chrono::{DateTime, TimeZone, Utc};
+use axum_extra::extract::CookieJar;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
 use humantime::format_duration;
-use maud::{html, Markup};
+use maud::{html, Markup, PreEscaped, Render};
 use mime_guess::{mime, Mime};
 use opendal::Operator;
 use relative_path::{RelativePath, RelativePathBuf};
-use uuid::Uuid;
 
+use super::unlock::password_prompt;
 use crate::{
+    collection::{files_directory, read_collection, Collection},
     components::{error_page::error_page, page::page},
-    util::get_directory_for_expiration,
+    config::Config,
+    downloads::read_downloads,
+    encryption::{KeyQuery, ShareKey},
+    highlight::highlight_lines,
+    manifest::{read_manifest, Manifest, ManifestError},
+    owner::{check_owner_token, manage_url, removal_cookie, OWNER_TOKEN_COOKIE},
+    parts::{parts_size, read_parts_range},
+    password::is_unlocked,
+    redirects::{moved_uri, resolve_redirect},
+    session::Signer,
+    tombstone::{read_tombstone, Removal},
+    util::{
+        format_size, get_directory_for_expiration, get_expiration_for_file_name, FileNameError,
+    },
 };
 
+/// Only this much of a text file is shown on its page, the rest of it has to
+/// be downloaded.
+const MAX_VIEWER_SIZE: u64 = 1000 * 1000;
+
 #[derive(thiserror::Error, Debug)]
 pub enum GetError {
     #[error("Invalid filename is not UUID.EXT.")]
@@ -48,39 +68,113 @@ impl IntoResponse for GetError {
     }
 }
 
+impl From<FileNameError> for GetError {
+    fn from(error: FileNameError) -> Self {
+        match error {
+            FileNameError::InvalidFileName => GetError::InvalidFileName,
+            FileNameError::InvalidUUIDVersion => GetError::InvalidUUIDVersion,
+            FileNameError::InvalidUUIDTimestamp => GetError::InvalidUUIDTimestamp,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn get(
     State(storage): State<Operator>,
+    State(signer): State<Signer>,
+    State(config): State<Arc<Config>>,
     Path(file_name): Path<RelativePathBuf>,
-) -> Result<Markup, GetError> {
-    let uuid = file_name
-        .file_stem()
-        .and_then(|stem| Uuid::try_parse(stem).ok())
-        .ok_or(GetError::InvalidFileName)?;
-
-    let expiration_timestamp = uuid.get_timestamp().ok_or(GetError::InvalidUUIDVersion)?;
-    let (seconds, subsec_nanos) = expiration_timestamp.to_unix();
-    let expiration_datetime = match chrono::Utc.timestamp_opt(seconds as i64, subsec_nanos) {
-        chrono::offset::LocalResult::Single(datetime) => Ok(datetime),
-        _ => Err(GetError::InvalidUUIDTimestamp),
-    }?;
+    Query(key_query): Query<KeyQuery>,
+    cookies: CookieJar,
+    uri: Uri,
+) -> Result<Response, GetError> {
+    let expiration_datetime = get_expiration_for_file_name(&file_name)?;
 
     let now = chrono::Utc::now();
-    let file_exists = if now < expiration_datetime {
-        let directory = get_directory_for_expiration(expiration_datetime);
-        let path = directory.join(format!("{file_name}/"));
-
-        match storage.stat(path.as_str()).await {
-            Ok(_) => Ok(true),
-            Err(err) => match err.kind() {
-                opendal::ErrorKind::NotFound => Ok(false),
-                _ => Err(GetError::Unkown(err.into())),
-            },
+    let share_directory =
+        get_directory_for_expiration(expiration_datetime, config.bucket_duration).join(&file_name);
+    let manifest = if now < expiration_datetime {
+        match read_manifest(&share_directory, &storage).await {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(ManifestError::NotFound) => Ok(None),
+            Err(err) => Err(GetError::Unkown(err.into())),
         }?
     } else {
-        false
+        None
+    };
+    let tombstone = match (&manifest, now < expiration_datetime) {
+        (None, true) => read_tombstone(&share_directory, &storage)
+            .await
+            .map_err(|err| GetError::Unkown(err.into()))?,
+        _ => None,
+    };
+    // It could have been moved to another expiration, expired or not.
+    if manifest.is_none() && tombstone.is_none() {
+        if let Some(new_file_name) = resolve_redirect(&file_name, &storage)
+            .await
+            .map_err(|err| GetError::Unkown(err.into()))?
+        {
+            return Ok(Redirect::to(&moved_uri(&uri, &file_name, &new_file_name)).into_response());
+        }
+    }
+    // Or it could be a collection of files, rather than a file.
+    if manifest.is_none() && tombstone.is_none() && now < expiration_datetime {
+        if let Some(collection) = read_collection(&share_directory, &storage)
+            .await
+            .map_err(|err| GetError::Unkown(err.into()))?
+        {
+            let (cookies, owner_token) = take_owner_token(
+                cookies,
+                &file_name,
+                collection.owner_token_digest.as_deref(),
+            );
+            let collection_page = collection_page(
+                &file_name,
+                &collection,
+                expiration_datetime,
+                files_directory(&share_directory),
+                &key_query,
+                owner_token.as_deref(),
+                &storage,
+            )
+            .await?;
+            return Ok((cookies, collection_page).into_response());
+        }
+    }
+    let downloads_left = match manifest
+        .as_ref()
+        .and_then(|manifest| manifest.max_downloads)
+    {
+        Some(max_downloads) => Some(
+            max_downloads.saturating_sub(
+                read_downloads(&share_directory, &storage)
+                    .await
+                    .map_err(|err| GetError::Unkown(err.into()))?,
+            ),
+        ),
+        None => None,
     };
 
-    let file_source = format!("/file/{file_name}");
+    // Nothing about the file besides when it expires is shown until the password
+    // to it is entered, not even its name or size.
+    let locked = manifest.as_ref().is_some_and(|manifest| {
+        manifest.password_hash.is_some() && !is_unlocked(&cookies, &file_name, &signer)
+    });
+
+    let key = match &manifest {
+        Some(manifest) if manifest.finalized => manifest.check_key(key_query.key.as_deref()),
+        _ => Ok(None),
+    };
+    // The key has to tag along to wherever the file is downloaded from.
+    let file_source = match &key {
+        Ok(Some(key)) => format!("/file/{file_name}?key={key}"),
+        _ => format!("/file/{file_name}"),
+    };
+    // Downloading has to save the file, rather than show it like the viewer does.
+    let download_source = match &key {
+        Ok(Some(_)) => format!("{file_source}&disposition=attachment"),
+        _ => format!("{file_source}?disposition=attachment"),
+    };
     let expires_in = (expiration_datetime - now)
         .to_std()
         .map(|duration| {
@@ -88,15 +182,34 @@ pub async fn get(
         })
         .unwrap_or_else(|_| "UNABLE TO PARSE".into());
 
-    let mime_type = file_name
-        .extension()
-        .and_then(|extension| mime_guess::from_ext(extension).first());
+    let mime_type = manifest
+        .as_ref()
+        .and_then(|manifest| manifest.media_type(&file_name));
 
     tracing::debug!("{:?}", mime_type);
 
-    let file_viewer = if let Some(possible_viewer) =
-        mime_type.map(|mime| file_viewer(&file_name, mime, expiration_datetime, &storage))
-    {
+    let file_viewer = if let Some(possible_viewer) = mime_type
+        .zip(
+            // Previews would use up downloads of files that are limited.
+            manifest.as_ref().filter(|manifest| {
+                manifest.finalized
+                    && !manifest.end_to_end_encrypted
+                    && manifest.max_downloads.is_none()
+                    && !locked
+            }),
+        )
+        .zip(key.as_ref().ok())
+        .map(|((mime, manifest), key)| {
+            file_viewer(
+                &file_source,
+                mime,
+                &share_directory,
+                manifest,
+                key.as_ref(),
+                true,
+                &storage,
+            )
+        }) {
         Some(possible_viewer.await)
     } else {
         None
@@ -107,6 +220,288 @@ pub async fn get(
     .flatten()
     .flatten();
 
+    let (cookies, owner_token) = take_owner_token(
+        cookies,
+        &file_name,
+        manifest
+            .as_ref()
+            .and_then(|manifest| manifest.owner_token_digest.as_deref()),
+    );
+
+    // Shown as whatever it was uploaded as, rather than the name it is shared under.
+    let display_name = match &manifest {
+        Some(manifest) if !locked => manifest.display_name(&file_name),
+        _ => file_name.to_string(),
+    };
+
+    let timer_script = format!(
+        "init repeat forever wait 1s then js return formatDuration(new Date(\"{}\") - new Date()) end then put it into me end",
+        expiration_datetime
+    );
+
+    Ok((
+        cookies,
+        page(
+            html! {
+                fieldset {
+                    h2 { "Viewing " code { (display_name) }}
+                    @if let Some(owner_token) = owner_token {
+                        p {
+                            "You can change when this file expires or delete it at "
+                            a href=(manage_url(file_name.as_str(), &owner_token)) { "this link" }
+                            ", which won't be shown again. Keep it to yourself!"
+                        }
+                    }
+                    @match (&manifest, &key) {
+                        (Some(manifest), _) if !manifest.finalized => {
+                            p {
+                                "This file "
+                                @if !locked { "(" (format_size(manifest.size)) ") " }
+                                "is still being uploaded, check back once the upload finishes."
+                            }
+                        }
+                        (Some(_), Err(err)) => {
+                            p { (err) " Make sure you have the whole link you were given." }
+                        }
+                        (Some(manifest), Ok(_)) => {
+                            p {
+                                "This file "
+                                @if !locked { "(" (format_size(manifest.size)) ") " }
+                                "expires in "
+                                time _=(timer_script) {
+                                    (expires_in)
+                                }
+                                "."
+                            }
+                            @if locked {
+                                (password_prompt(&file_name, uri.query(), None))
+                            } @else {
+                                @if let Some(downloads_left) = downloads_left {
+                                    p {
+                                        @match downloads_left {
+                                            1 => "It can only be downloaded once more, after which it's gone.",
+                                            _ => { "It can be downloaded " (downloads_left) " more times, after which it's gone." },
+                                        }
+                                    }
+                                }
+                                @if manifest.end_to_end_encrypted {
+                                    p {
+                                        "It is end-to-end encrypted, so it can only be decrypted here in "
+                                        "your browser with the key at the end of the link."
+                                    }
+                                    ul {
+                                        li {
+                                            a href=(file_source) _=(end_to_end_download_script(&display_name, manifest)) {
+                                                "Download"
+                                            }
+                                            " "
+                                            progress id="decrypt-progress" value=(0) max=(100) hidden {}
+                                        }
+                                        br;
+                                        li {
+                                            // The key is in the fragment, which an empty link would drop.
+                                            a href="" _="init set my href to window.location.href" { "Share" }
+                                            " (Right click and choose \"Copy Link Address\")"
+                                        }
+                                    }
+                                    em id="decrypt-error" {}
+                                } @else {
+                                    ul {
+                                        li { a href=(download_source) download=(display_name) { "Download" } }
+                                        br;
+                                        li {
+                                            a href="" { "Share" }
+                                            " (Right click and choose \"Copy Link Address\")"
+                                        }
+                                    }
+                                }
+                                @if let Some(file_viewer) = file_viewer {
+                                    br;
+                                    (file_viewer)
+                                    br;
+                                }
+                            }
+                        }
+                        (None, _) => {
+                            @match tombstone.map(|tombstone| tombstone.removal) {
+                                Some(Removal::Consumed) => p {
+                                    "This file has been consumed, it was downloaded as many times as "
+                                    "it was allowed to be."
+                                },
+                                Some(Removal::Deleted) => p {
+                                    "This file has been deleted by whoever shared it."
+                                },
+                                None => p {
+                                    "This file has either expired or never even existed in the first place."
+                                },
+                            }
+                        }
+                    }
+                }
+            },
+            false,
+        ),
+    )
+        .into_response())
+}
+
+/// The owner token is only ever shown once, right after the upload, so the
+/// cookie it comes in is removed as soon as it is picked up here. It is only
+/// returned if it is the one `owner_token_digest` is the digest of.
+fn take_owner_token(
+    cookies: CookieJar,
+    file_name: &RelativePath,
+    owner_token_digest: Option<&str>,
+) -> (CookieJar, Option<String>) {
+    match cookies.get(OWNER_TOKEN_COOKIE) {
+        Some(cookie) => {
+            let token = cookie.value().to_string();
+            let is_owner = check_owner_token(owner_token_digest, &token);
+            (
+                cookies.remove(removal_cookie(file_name.as_str())),
+                is_owner.then_some(token),
+            )
+        }
+        None => (cookies, None),
+    }
+}
+
+/// Lists every file in `collection` along with a preview of it, where each of
+/// them is stored in `directory` next to the collection.
+async fn collection_page(
+    collection_name: &RelativePath,
+    collection: &Collection,
+    expiration_datetime: DateTime<Utc>,
+    directory: &RelativePath,
+    key_query: &KeyQuery,
+    owner_token: Option<&str>,
+    storage: &Operator,
+) -> Result<Markup, GetError> {
+    let mut files = Vec::new();
+    for file in &collection.files {
+        let share_directory = directory.join(&file.file_name);
+        let manifest = match read_manifest(&share_directory, storage).await {
+            Ok(manifest) => Some(manifest),
+            Err(ManifestError::NotFound) => None,
+            Err(err) => return Err(GetError::Unkown(err.into())),
+        };
+        files.push((file, share_directory, manifest));
+    }
+    let total_size = files
+        .iter()
+        .filter_map(|(_, _, manifest)| manifest.as_ref())
+        .map(|manifest| manifest.size)
+        .sum();
+
+    // Every file in a collection is encrypted with the same key.
+    let key = files
+        .iter()
+        .find_map(|(_, _, manifest)| manifest.as_ref())
+        .map_or(Ok(None), |manifest| {
+            manifest.check_key(key_query.key.as_deref())
+        });
+
+    // Folders can hold far too many files to preview them all at once, so
+    // those are laid out as a tree that links to each of them instead.
+    let mut entries = Vec::new();
+    let mut tree = collection.is_folder().then(FolderTree::default);
+    if let Ok(key) = &key {
+        for (file, share_directory, manifest) in &files {
+            let file_name = RelativePath::new(&file.file_name);
+            let Some(manifest) = manifest else {
+                match (&mut tree, &file.path) {
+                    (Some(tree), Some(path)) => tree.insert(
+                        path,
+                        html! { code { (path.file_name().unwrap_or_default()) } " (no longer available)" },
+                    ),
+                    _ => entries.push(html! {
+                        h3 { code { (file_name) } }
+                        p { "This file is no longer available." }
+                    }),
+                }
+                continue;
+            };
+
+            let display_name = manifest.display_name(file_name);
+            let query = key
+                .as_ref()
+                .map_or_else(String::new, |key| format!("?key={key}"));
+            let view_source = format!("/file/{file_name}/view{query}");
+            let download_source = match key {
+                Some(_) => format!("/file/{file_name}{query}&disposition=attachment"),
+                None => format!("/file/{file_name}?disposition=attachment"),
+            };
+
+            if let Some(tree) = &mut tree {
+                let path = file
+                    .path
+                    .clone()
+                    .unwrap_or_else(|| RelativePathBuf::from(&display_name));
+                tree.insert(
+                    &path,
+                    html! {
+                        code { (display_name) } " (" (format_size(manifest.size)) ") "
+                        @if manifest.finalized {
+                            a href=(view_source) { "Preview" }
+                            " "
+                            a href=(download_source) download=(display_name) { "Download" }
+                        } @else {
+                            "is still being uploaded."
+                        }
+                    },
+                );
+                continue;
+            }
+
+            let file_viewer = match manifest.media_type(file_name) {
+                Some(mime) if manifest.finalized => file_viewer(
+                    &format!("/file/{file_name}{query}"),
+                    mime,
+                    share_directory,
+                    manifest,
+                    key.as_ref(),
+                    // Every file in the collection would have the same anchors.
+                    false,
+                    storage,
+                )
+                .await
+                .inspect_err(|err| {
+                    tracing::error!("Failed to create viewer for {}: {}", file_name, err)
+                })
+                .ok()
+                .flatten(),
+                _ => None,
+            };
+
+            entries.push(html! {
+                h3 { a href=(view_source) { code { (display_name) } } }
+                @if manifest.finalized {
+                    p {
+                        a href=(download_source) download=(display_name) { "Download" }
+                        " (" (format_size(manifest.size)) ")"
+                    }
+                } @else {
+                    p { "This file (" (format_size(manifest.size)) ") is still being uploaded." }
+                }
+                @if let Some(file_viewer) = file_viewer {
+                    (file_viewer)
+                    br;
+                }
+            });
+        }
+    }
+
+    let archive_source = match &key {
+        Ok(Some(key)) => format!("/file/{collection_name}/archive.zip?key={key}"),
+        _ => format!("/file/{collection_name}/archive.zip"),
+    };
+    let now = chrono::Utc::now();
+    let expires_in = (expiration_datetime - now)
+        .to_std()
+        .map(|duration| {
+            format_duration(std::time::Duration::from_secs(duration.as_secs())).to_string()
+        })
+        .unwrap_or_else(|_| "UNABLE TO PARSE".into());
     let timer_script = format!(
         "init repeat forever wait 1s then js return formatDuration(new Date(\"{}\") - new Date()) end then put it into me end",
         expiration_datetime
@@ -115,30 +510,43 @@ pub async fn get(
     Ok(page(
         html! {
             fieldset {
-                h2 { "Viewing " code { (file_name) }}
-                @if file_exists {
+                h2 { "Viewing " (collection.files.len()) " files" }
+                @if let Some(owner_token) = owner_token {
                     p {
-                        "This file expires in "
-                        time _=(timer_script) {
-                            (expires_in)
-                        }
-                        "."
+                        "You can change when these files expire or delete them at "
+                        a href=(manage_url(collection_name.as_str(), owner_token)) { "this link" }
+                        ", which won't be shown again. Keep it to yourself!"
                     }
-                    ul {
-                        li { a href=(file_source) download=(file_name) { "Download" } }
-                        br;
-                        li {
-                            a href="" { "Share" }
-                            " (Right click and choose \"Copy Link Address\")"
-                        }
+                }
+                @match &key {
+                    Err(err) => {
+                        p { (err) " Make sure you have the whole link you were given." }
                     }
-                    @if let Some(file_viewer) = file_viewer {
-                        br;
-                        (file_viewer)
-                        br;
+                    Ok(_) => {
+                        p {
+                            "These files (" (format_size(total_size)) ") expire in "
+                            time _=(timer_script) {
+                                (expires_in)
+                            }
+                            "."
+                        }
+                        ul {
+                            li { a href=(archive_source) download { "Download all" } " (ZIP)" }
+                            br;
+                            li {
+                                a href="" { "Share" }
+                                " (Right click and choose \"Copy Link Address\")"
+                            }
+                        }
+                        @if let Some(tree) = tree {
+                            hr;
+                            (tree)
+                        }
+                        @for entry in entries {
+                            hr;
+                            (entry)
+                        }
                     }
-                } @else {
-                    p { "This file has either expired or never even existed in the first place." }
                 }
             }
         },
@@ -146,13 +554,83 @@ pub async fn get(
     ))
 }
 
+/// The files of a collection that was uploaded as a folder, arranged the way
+/// they were in it.
+#[derive(Default)]
+struct FolderTree {
+    folders: BTreeMap<String, FolderTree>,
+    /// Sorted by name, alongside what is shown for each of them.
+    files: Vec<(String, Markup)>,
+}
+
+impl FolderTree {
+    fn insert(&mut self, path: &RelativePath, entry: Markup) {
+        let mut folder = self;
+        if let Some(parent) = path.parent() {
+            for name in parent.iter() {
+                folder = folder.folders.entry(name.to_string()).or_default();
+            }
+        }
+
+        let name = path.file_name().unwrap_or_default().to_string();
+        let index = folder
+            .files
+            .partition_point(|(file_name, _)| *file_name <= name);
+        folder.files.insert(index, (name, entry));
+    }
+}
+
+impl Render for FolderTree {
+    fn render(&self) -> Markup {
+        html! {
+            ul {
+                @for (name, folder) in &self.folders {
+                    li {
+                        details open {
+                            summary { code { (name) "/" } }
+                            (folder)
+                        }
+                    }
+                }
+                @for (_, entry) in &self.files {
+                    li { (entry) }
+                }
+            }
+        }
+    }
+}
+
+/// Downloads and decrypts an end-to-end encrypted file in the browser, which
+/// needs to know where each part starts since each is encrypted on its own.
+///
+/// The file is fetched from wherever the link it is on points to.
+fn end_to_end_download_script(file_name: &str, manifest: &Manifest) -> String {
+    let part_sizes = manifest
+        .parts
+        .iter()
+        .map(|part| part.as_ref().map_or(0, |part| part.size).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    // The name is whatever it was uploaded as, so it has to be quoted properly.
+    let file_name = serde_json::to_string(file_name).unwrap_or_default();
+
+    format!(
+        "on click halt the event then call downloadEndToEndEncrypted(@href, {file_name}, [{part_sizes}])"
+    )
+}
+
+/// Shows the file right on the page if it's something browsers can show, with
+/// text highlighted and numbered by line. With `line_anchors` each line can be
+/// linked to as `#L10`, or a range of them as `#L10-L20`.
 async fn file_viewer(
-    file_name: &RelativePath,
+    file_source: &str,
     mime: Mime,
-    expiration_datetime: DateTime<Utc>,
+    share_directory: &RelativePath,
+    manifest: &Manifest,
+    key: Option<&ShareKey>,
+    line_anchors: bool,
     storage: &Operator,
 ) -> anyhow::Result<Option<Markup>> {
-    let file_source = format!("/file/{file_name}");
     match (mime.type_(), mime.subtype()) {
         (mime::VIDEO, _) => Ok(Some(html!(
             center {
@@ -174,17 +652,58 @@ async fn file_viewer(
             }
         ))),
         (mime::TEXT, _) => {
-            let directory = get_directory_for_expiration(expiration_datetime);
-            let file_path = directory.join(file_name);
+            let Some(parts) = manifest.file_parts(share_directory) else {
+                return Ok(None);
+            };
 
-            let bytes = storage.read(file_path.as_str()).await?;
-            let content = from_utf8(&bytes)?;
+            let size = parts_size(&parts);
+            let truncated = size > MAX_VIEWER_SIZE;
+            let mut bytes = read_parts_range(&parts, 0..size.min(MAX_VIEWER_SIZE), key, storage)
+                .try_fold(Vec::new(), |mut bytes, chunk| async move {
+                    bytes.extend_from_slice(&chunk);
+                    Ok(bytes)
+                })
+                .await?;
+            // Cutting the text off can leave half of a character at the end.
+            if let Err(err) = from_utf8(&bytes) {
+                match err.error_len() {
+                    None if truncated => bytes.truncate(err.valid_up_to()),
+                    _ => return Err(err.into()),
+                }
+            }
+            let content = String::from_utf8(bytes)?;
+            let file_name = share_directory
+                .file_name()
+                .map_or(share_directory, RelativePath::new);
+            let lines = highlight_lines(content, manifest.display_name(file_name)).await?;
+            let anchors_script = line_anchors.then_some(
+                "init call highlightLines()
+                on hashchange from window call highlightLines()
+                on click call selectLines(event)",
+            );
 
             Ok(Some(html!(
                 hr;
-                pre {
-                    code {
-                        (content)
+                pre.hl-code.code-viewer _=[anchors_script] {
+                    @for (index, line) in lines.iter().enumerate() {
+                        @let number = index + 1;
+                        @if line_anchors {
+                            span.line id=(format!("L{number}")) {
+                                a.line-number href=(format!("#L{number}")) data-line=(number) {}
+                                code { (PreEscaped(line)) }
+                            }
+                        } @else {
+                            span.line {
+                                span.line-number data-line=(number) {}
+                                code { (PreEscaped(line)) }
+                            }
+                        }
+                    }
+                }
+                @if truncated {
+                    sub {
+                        "Only the first " (format_size(MAX_VIEWER_SIZE)) " are shown, "
+                        "download it to see the rest."
                     }
                 }
                 hr;
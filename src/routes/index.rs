@@ -1,45 +1,113 @@
+use std::sync::Arc;
+
 use axum::{
+    body::Bytes,
     extract::{multipart::Field, Multipart, State},
     response::{IntoResponse, Redirect, Response},
 };
-use axum_htmx::HxReswap;
+use axum_extra::extract::CookieJar;
+use axum_htmx::{HxRedirect, HxRequest, HxReswap};
 use chrono::{DateTime, Utc};
-use futures::TryStreamExt;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
 use maud::{html, Markup, Render};
+use mime_guess::mime;
 use opendal::Operator;
 use relative_path::RelativePath;
 
 use crate::{
-    components::page::page,
-    util::{get_directory_for_expiration, write_file, DatetimeUUIDv7GeneratorExt},
+    blobs::{blob_key, reference_blob, BlobError},
+    collection::{write_collection, Collection, CollectionFile},
+    components::{page::page, share_for::share_for_field},
+    config::{Config, ExpirationError},
+    encryption::ShareKey,
+    languages::{language_extension, LANGUAGES},
+    manifest::{finalize_manifest, read_manifest, record_part, write_manifest, Manifest},
+    owner::OwnerToken,
+    parts::part_path,
+    password::hash_password,
+    session::{Signer, UploadSession},
+    state::Settings,
+    tombstone::{remove_share, Removal},
+    util::{
+        get_directory_for_expiration, sanitize_file_name, sanitize_relative_path, share_file_name,
+        write_file, WriteFileOptions,
+    },
 };
 
-static SHARE_FOR_OPTIONS: phf::OrderedMap<&str, chrono::Duration> = phf::phf_ordered_map! {
-    "30 minutes" => chrono::Duration::minutes(30),
-    "1 hour" => chrono::Duration::hours(1),
-    "6 hours" => chrono::Duration::hours(6),
-    "12 hours" => chrono::Duration::hours(12),
-    "1 day" => chrono::Duration::days(1),
-    "3 days" => chrono::Duration::days(3)
-};
+fn index_page(config: &Config, settings: &Settings, error: Option<&dyn Render>) -> Markup {
+    // Encrypted files are never deduplicated, so there's no point in the
+    // browser checksumming them up front.
+    let deduplicate = settings.deduplicate && !settings.encrypt;
 
-fn index_page(error: Option<&dyn Render>) -> Markup {
     page(
         html! {
             form method="post" enctype="multipart/form-data"
             _="on htmx:configRequest(event) if event.detail.elt is me js configRequestParts(event) end end" {
                 fieldset {
                     h2 { "Share file" }
-                    label for="share-for" { "Share for: " }
-                    select id="share-for" name="Share for" {
-                        @for &share_for_option in SHARE_FOR_OPTIONS.keys() {
-                            option { (share_for_option) }
-                        }
-                    }
+                    (share_for_field(config))
                     br;br;
-                    label for="file" { "File: " }
-                    input id="file" type="file" accept="*" name="File" required;
+                    label for="max-downloads" { "Max downloads: " }
+                    input id="max-downloads" type="number" name="Max downloads" min="1"
+                    placeholder="Unlimited";
+                    br;
+                    sub { "Once it has been downloaded this many times it's gone, 1 to burn after reading." }
                     br;br;
+                    label for="password" { "Password: " }
+                    input id="password" type="password" name="Password" autocomplete="new-password"
+                    placeholder="None";
+                    br;
+                    sub { "Has to be entered before the file can be viewed or downloaded." }
+                    br;br;
+                    input id="end-to-end" type="checkbox" name="End-to-end encrypt";
+                    label for="end-to-end" {
+                        " End-to-end encrypt (only people with the link can decrypt it, "
+                        "and only in their browser)"
+                    }
+                    br;br;
+                    div role="tablist" {
+                        button type="button" role="tab"
+                        _="on click
+                            remove @hidden from #file-tab then remove @disabled from #file-tab
+                            add @hidden to #paste-tab then add @disabled to #paste-tab" {
+                            "File"
+                        }
+                        " "
+                        button type="button" role="tab"
+                        _="on click
+                            remove @hidden from #paste-tab then remove @disabled from #paste-tab
+                            add @hidden to #file-tab then add @disabled to #file-tab" {
+                            "Paste text"
+                        }
+                    }
+                    br;
+                    // Whichever tab isn't shown is disabled, so it is neither
+                    // required nor sent along.
+                    fieldset id="file-tab" role="tabpanel" {
+                        label for="file" { "File: " }
+                        input id="file" type="file" accept="*" name="File" required multiple
+                        data-part-size=(config.part_size)
+                        data-deduplicate=[deduplicate.then_some("")]
+                        _="on change call checksumFile(me)";
+                        br;
+                        sub { "Several files are shared together under a single link." }
+                        br;br;
+                        input id="folder" type="checkbox"
+                        _="on change js(me) document.getElementById('file').value = ''; document.getElementById('file').webkitdirectory = me.checked end";
+                        label for="folder" { " Share a folder instead, keeping the files in it where they are" }
+                    }
+                    fieldset id="paste-tab" role="tabpanel" hidden disabled {
+                        label for="language" { "Language: " }
+                        select id="language" name="Language" {
+                            @for (name, _) in LANGUAGES {
+                                option { (name) }
+                            }
+                        }
+                        br;br;
+                        textarea id="text" name="Text" aria-label="Text" rows="16" required
+                        placeholder="Paste a log excerpt, a config snippet or anything else here." {}
+                    }
+                    br;
                     input type="submit" data-loading-disable data-loading-aria-busy;
                     br;
                     br;
@@ -57,7 +125,9 @@ fn index_page(error: Option<&dyn Render>) -> Markup {
     )
 }
 
-pub async fn get() -> Markup { index_page(None) }
+pub async fn get(State(config): State<Arc<Config>>, State(settings): State<Settings>) -> Markup {
+    index_page(&config, &settings, None)
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum PostError {
@@ -65,25 +135,57 @@ pub enum PostError {
     MissingField(&'static str),
     #[error("Missing file name.")]
     MissingFileName,
-    #[error("Unkown file type.")]
-    UnknownFileType,
+    #[error("'{0}' isn't a valid file name, or leads outside of the folder being shared.")]
+    InvalidPath(String),
+    #[error("End-to-end encryption happens in the browser, and requires JavaScript.")]
+    EndToEndRequiresJavaScript,
+    #[error("'{0}' isn't one of the languages text can be pasted as.")]
+    UnknownLanguage(String),
+    #[error(transparent)]
+    InvalidExpiration(#[from] ExpirationError),
+    #[error("Max downloads has to be a whole number above zero, or left empty for no limit.")]
+    InvalidMaxDownloads,
+    #[error("Max downloads and passwords can only be set when sharing a single file.")]
+    OptionsRequireSingleFile,
+    #[error("'Parts' doesn't match how the file should have been split up.")]
+    InvalidParts,
+    #[error("Files can be split into at most {0} parts.")]
+    TooManyParts(usize),
     #[error("Unkown error.")]
     Unkown(#[from] anyhow::Error),
 }
 
-impl IntoResponse for PostError {
-    fn into_response(self) -> Response {
+impl PostError {
+    /// Errors are shown on the index page, which depends on the config.
+    fn into_page(self, config: &Config, settings: &Settings) -> Markup {
         if let PostError::Unkown(error) = &self {
             tracing::error!("Unkown error encountered for user: {error}");
         }
 
-        index_page(Some(&self.to_string())).into_response()
+        index_page(config, settings, Some(&self.to_string()))
     }
 }
 
 pub async fn post(
     State(storage): State<Operator>,
+    State(signer): State<Signer>,
+    State(settings): State<Settings>,
+    State(config): State<Arc<Config>>,
+    HxRequest(hx_request): HxRequest,
+    multipart: Multipart,
+) -> Response {
+    share(multipart, hx_request, &settings, &config, &storage, &signer)
+        .await
+        .unwrap_or_else(|err| err.into_page(&config, &settings).into_response())
+}
+
+async fn share(
     mut multipart: Multipart,
+    hx_request: bool,
+    settings: &Settings,
+    config: &Config,
+    storage: &Operator,
+    signer: &Signer,
 ) -> Result<Response, PostError> {
     // Use the Share For field to create a timestamped UUID with the expiration date
     // This lets us avoid needing to use any sort of other persistance such as a
@@ -94,24 +196,110 @@ pub async fn post(
         .await
         .map_err(|err| PostError::Unkown(err.into()))?;
 
-    let share_for = *SHARE_FOR_OPTIONS
-        .get(share_for_field_value.as_str())
-        .unwrap_or(
-            SHARE_FOR_OPTIONS
-                .values()
-                .next()
-                .expect("at least one share for option"),
-        );
-    let expiration_datetime = chrono::Utc::now() + share_for;
+    let expiration_datetime = config.expiration_for(&share_for_field_value, chrono::Utc::now())?;
 
-    let field = get_next_multipart_field(&mut multipart)
+    let mut field = get_next_multipart_field(&mut multipart)
         .await?
-        .ok_or(PostError::MissingField("File or Parts"))?;
+        .ok_or(PostError::MissingField("File, Parts or Text"))?;
+    // The download limit is optional, and left empty for no limit.
+    let mut max_downloads = None;
+    if field.name() == Some("Max downloads") {
+        let value = field
+            .text()
+            .await
+            .map_err(|err| PostError::Unkown(err.into()))?;
+        if !value.trim().is_empty() {
+            max_downloads = Some(
+                value
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&max_downloads| max_downloads > 0)
+                    .ok_or(PostError::InvalidMaxDownloads)?,
+            );
+        }
+        field = get_next_multipart_field(&mut multipart)
+            .await?
+            .ok_or(PostError::MissingField("File, Parts or Text"))?;
+    }
+    // So is the password, which is left empty for none.
+    let mut password_hash = None;
+    if field.name() == Some("Password") {
+        let password = field
+            .text()
+            .await
+            .map_err(|err| PostError::Unkown(err.into()))?;
+        if !password.is_empty() {
+            password_hash = Some(hash_password(password).await?);
+        }
+        field = get_next_multipart_field(&mut multipart)
+            .await?
+            .ok_or(PostError::MissingField("File, Parts or Text"))?;
+    }
+
+    // The language of pasted text comes right before it, see [`LANGUAGES`].
+    let mut extension = LANGUAGES[0].1;
+    if field.name() == Some("Language") {
+        let language = field
+            .text()
+            .await
+            .map_err(|err| PostError::Unkown(err.into()))?;
+        extension = language_extension(&language).ok_or(PostError::UnknownLanguage(language))?;
+        field = get_next_multipart_field(&mut multipart)
+            .await?
+            .ok_or(PostError::MissingField("Text"))?;
+    }
+
+    let options = ShareOptions {
+        expiration_datetime,
+        max_downloads,
+        password_hash,
+        owner_token: OwnerToken::generate(),
+    };
     match field.name() {
+        Some("Text") => {
+            share_text_and_redirect(
+                field, extension, &options, hx_request, settings, config, storage,
+            )
+            .await
+        }
         Some("File") => {
-            upload_file_in_single_part_and_redirect(field, expiration_datetime, &storage)
-                .await
-                .map(|redirect| redirect.into_response())
+            // The key is only ever handed back in the link, which is the same
+            // for every file in a collection.
+            let key = settings.encrypt.then(ShareKey::generate);
+            let mut files = vec![
+                upload_file_in_single_part(
+                    field,
+                    key.as_ref(),
+                    &options,
+                    settings,
+                    config,
+                    storage,
+                )
+                .await?,
+            ];
+            // None of them have been handed out yet, so whatever made it in
+            // before something went wrong would just be left behind.
+            if let Err(err) = upload_remaining_files(
+                &mut multipart,
+                &mut files,
+                key.as_ref(),
+                &options,
+                settings,
+                config,
+                storage,
+            )
+            .await
+            {
+                discard_shares(&files, &options, config, storage).await;
+                return Err(err);
+            }
+
+            let file_name = match files.as_slice() {
+                [file] => file.file_name.clone(),
+                _ => share_collection(files, &options, config, storage).await?,
+            };
+            redirect_to_view(&file_name, key.as_ref(), &options, hx_request)
         }
         Some("Parts") => {
             let parts = field
@@ -129,72 +317,472 @@ pub async fn post(
                 .text()
                 .await
                 .map_err(|err| PostError::Unkown(err.into()))?;
-            upload_file_in_parts_and_redirect(&file_name, parts, expiration_datetime, &storage)
+            let size = get_and_validate_multipart_field("Size", &mut multipart)
+                .await?
+                .text()
                 .await
-                .map(|markup| (HxReswap(axum_htmx::SwapOption::None), markup).into_response())
+                .map_err(|err| PostError::Unkown(err.into()))
+                .and_then(|string| {
+                    string
+                        .parse::<u64>()
+                        .map_err(|err| PostError::Unkown(err.into()))
+                })?;
+            // The rest of the fields are optional.
+            let mut checksum = None;
+            let mut end_to_end = false;
+            while let Some(field) = get_next_multipart_field(&mut multipart).await? {
+                match field.name() {
+                    // Only used to skip uploading content that is already stored.
+                    Some("Checksum") => {
+                        checksum = Some(
+                            field
+                                .text()
+                                .await
+                                .map_err(|err| PostError::Unkown(err.into()))?,
+                        )
+                        .filter(|checksum| !checksum.is_empty())
+                    }
+                    Some("End-to-end encrypt") => end_to_end = true,
+                    _ => {}
+                }
+            }
+            let parts = validate_parts(parts, size, end_to_end, config)?;
+            upload_file_in_parts_and_redirect(
+                sanitize_file_name(&file_name).ok_or(PostError::MissingFileName)?,
+                size,
+                parts,
+                checksum,
+                end_to_end,
+                &options,
+                hx_request,
+                settings,
+                config,
+                storage,
+                signer,
+            )
+            .await
         }
-        _ => Err(PostError::MissingField("File or Parts")),
+        // Without JavaScript the box comes through before the file, which can't
+        // be encrypted on the way.
+        Some("End-to-end encrypt") => Err(PostError::EndToEndRequiresJavaScript),
+        _ => Err(PostError::MissingField("File, Parts or Text")),
     }
 }
 
-async fn upload_file_in_single_part_and_redirect<'a>(
-    file_field: Field<'a>,
+/// Files uploaded in parts can be split into at most this many, which is also
+/// as many as S3 allows in a multipart upload.
+const MAX_PARTS: usize = 10_000;
+
+/// Bytes that end-to-end encryption adds to each part, for the IV and the tag,
+/// see `END_TO_END_PART_OVERHEAD` in `public/js/lib.js`.
+const END_TO_END_PART_OVERHEAD: u64 = 12 + 16;
+
+/// Checks that a file of `size` bytes was split into as many `parts` as the
+/// client is supposed to split it into, which is one per `part_size` bytes.
+fn validate_parts(
+    parts: usize,
+    size: u64,
+    end_to_end: bool,
+    config: &Config,
+) -> Result<usize, PostError> {
+    if parts > MAX_PARTS {
+        return Err(PostError::TooManyParts(MAX_PARTS));
+    }
+
+    // End-to-end encrypted files are split up before each part is encrypted.
+    let overhead = if end_to_end {
+        parts as u64 * END_TO_END_PART_OVERHEAD
+    } else {
+        0
+    };
+    let expected_parts = size
+        .checked_sub(overhead)
+        .map(|size| size.div_ceil(config.part_size).max(1));
+    if expected_parts == Some(parts as u64) {
+        Ok(parts)
+    } else {
+        Err(PostError::InvalidParts)
+    }
+}
+
+/// What was picked for the share, besides the file itself.
+struct ShareOptions {
     expiration_datetime: DateTime<Utc>,
+    max_downloads: Option<u64>,
+    password_hash: Option<String>,
+    /// Handed back once alongside the link to the file, see [`OwnerToken::cookie`].
+    owner_token: OwnerToken,
+}
+
+impl ShareOptions {
+    fn owner_cookie(&self, file_name: &str) -> CookieJar {
+        CookieJar::new().add(self.owner_token.cookie(file_name))
+    }
+}
+
+/// Uploads a file that came through as a whole, returning the file name it is
+/// shared under along with where it was in the folder it was uploaded in, if
+/// it was.
+async fn upload_file_in_single_part<'a>(
+    file_field: Field<'a>,
+    key: Option<&ShareKey>,
+    options: &ShareOptions,
+    settings: &Settings,
+    config: &Config,
     storage: &Operator,
-) -> Result<Redirect, PostError> {
-    let file_name = file_field
+) -> Result<CollectionFile, PostError> {
+    // Files in a folder come through with their path within it as their name.
+    let path = match file_field.file_name() {
+        None | Some("") => return Err(PostError::MissingFileName),
+        Some(file_name) => sanitize_relative_path(file_name)
+            .ok_or_else(|| PostError::InvalidPath(file_name.to_string()))?,
+    };
+    let file_name = path
         .file_name()
         .ok_or(PostError::MissingFileName)?
         .to_string();
 
-    if file_name.is_empty() {
-        return Err(PostError::MissingFileName);
+    let body_with_io_error = file_field
+        .map_err(|err| opendal::Error::new(opendal::ErrorKind::Unexpected, &err.body_text()));
+
+    let expiration_datetime = options.expiration_datetime;
+    let directory = get_directory_for_expiration(expiration_datetime, config.bucket_duration);
+    let shared_file_name = share_file_name(
+        expiration_datetime,
+        RelativePath::new(&file_name).extension(),
+    );
+    let share_directory = directory.join(&shared_file_name);
+
+    let manifest = Manifest {
+        key_digest: key.map(ShareKey::digest),
+        max_downloads: options.max_downloads,
+        owner_token_digest: Some(options.owner_token.digest()),
+        password_hash: options.password_hash.clone(),
+        ..Manifest::new(file_name, 0, 1)
+    };
+    write_share_in_single_part(
+        &share_directory,
+        &manifest,
+        body_with_io_error,
+        key,
+        settings,
+        config,
+        storage,
+    )
+    .await?;
+
+    Ok(CollectionFile {
+        file_name: shared_file_name,
+        path: (path.components().count() > 1).then_some(path),
+    })
+}
+
+/// Uploads the rest of the files that came through after the first one, adding
+/// each of them to `files`.
+async fn upload_remaining_files(
+    multipart: &mut Multipart,
+    files: &mut Vec<CollectionFile>,
+    key: Option<&ShareKey>,
+    options: &ShareOptions,
+    settings: &Settings,
+    config: &Config,
+    storage: &Operator,
+) -> Result<(), PostError> {
+    while let Some(field) = get_next_multipart_field(multipart).await? {
+        if field.name() != Some("File") {
+            continue;
+        }
+        if options.max_downloads.is_some() || options.password_hash.is_some() {
+            return Err(PostError::OptionsRequireSingleFile);
+        }
+        files.push(
+            upload_file_in_single_part(field, key, options, settings, config, storage).await?,
+        );
     }
 
-    let extension = RelativePath::new(&file_name)
-        .extension()
-        .ok_or(PostError::UnknownFileType)?
-        .to_string();
+    Ok(())
+}
 
-    let body_with_io_error = file_field
-        .map_err(|err| opendal::Error::new(opendal::ErrorKind::Unexpected, &err.body_text()));
+/// Removes the already shared `files`, for when the rest of them couldn't be.
+async fn discard_shares(
+    files: &[CollectionFile],
+    options: &ShareOptions,
+    config: &Config,
+    storage: &Operator,
+) {
+    let directory =
+        get_directory_for_expiration(options.expiration_datetime, config.bucket_duration);
+    for file in files {
+        let share_directory = directory.join(&file.file_name);
+        let removed = match read_manifest(&share_directory, storage).await {
+            Ok(manifest) => {
+                remove_share(&share_directory, &manifest, Removal::Deleted, storage).await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = removed {
+            tracing::error!("Unable to discard {share_directory}: {err}");
+        }
+    }
+}
+
+/// Shares text that was pasted in, as a file named after the `extension` of
+/// the language it is in.
+async fn share_text_and_redirect<'a>(
+    text_field: Field<'a>,
+    extension: &str,
+    options: &ShareOptions,
+    hx_request: bool,
+    settings: &Settings,
+    config: &Config,
+    storage: &Operator,
+) -> Result<Response, PostError> {
+    let body_with_io_error = convert_line_breaks(
+        text_field
+            .map_err(|err| opendal::Error::new(opendal::ErrorKind::Unexpected, &err.body_text())),
+    );
 
-    let directory = get_directory_for_expiration(expiration_datetime);
-    let uuid_string = expiration_datetime.generate_uuidv7().to_string();
-    let file_path = directory
-        .join(format!("{uuid_string}.{extension}"))
-        .join("0");
+    let expiration_datetime = options.expiration_datetime;
+    let file_name = share_file_name(expiration_datetime, Some(extension));
+    let share_directory =
+        get_directory_for_expiration(expiration_datetime, config.bucket_duration).join(&file_name);
 
-    write_file(&file_path, body_with_io_error, storage)
+    let key = settings.encrypt.then(ShareKey::generate);
+    // It's text whatever it looks like, so it is never shown as anything else.
+    let manifest = Manifest {
+        key_digest: key.as_ref().map(ShareKey::digest),
+        max_downloads: options.max_downloads,
+        owner_token_digest: Some(options.owner_token.digest()),
+        password_hash: options.password_hash.clone(),
+        content_type: Some(mime::TEXT_PLAIN_UTF_8.to_string()),
+        ..Manifest::new(format!("paste.{extension}"), 0, 1)
+    };
+    write_share_in_single_part(
+        &share_directory,
+        &manifest,
+        body_with_io_error,
+        key.as_ref(),
+        settings,
+        config,
+        storage,
+    )
+    .await?;
+
+    redirect_to_view(&file_name, key.as_ref(), options, hx_request)
+}
+
+/// Sends whoever shared `file_name` over to its view page, along with the
+/// cookie that lets them manage it.
+fn redirect_to_view(
+    file_name: &str,
+    key: Option<&ShareKey>,
+    options: &ShareOptions,
+    hx_request: bool,
+) -> Result<Response, PostError> {
+    let view_url = view_url(file_name, key);
+    // A redirect would just be followed along by htmx, so it is told to go to
+    // the view page itself instead.
+    if hx_request {
+        let view = view_url
+            .parse()
+            .map_err(|err: axum::http::uri::InvalidUri| PostError::Unkown(err.into()))?;
+        Ok((options.owner_cookie(file_name), HxRedirect(view), html! {}).into_response())
+    } else {
+        Ok((options.owner_cookie(file_name), Redirect::to(&view_url)).into_response())
+    }
+}
+
+/// Converts the CRLF line breaks browsers send every line break in a text area
+/// as, whatever they were when pasted in, into plain LFs. Any other carriage
+/// returns are left alone.
+fn convert_line_breaks<S, E>(body: S) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    // A CR at the end of a chunk can only be sorted out once the next one
+    // comes in, with `None` standing in for the end of the body.
+    body.map_ok(Some)
+        .chain(stream::iter([Ok(None)]))
+        .scan(false, |pending_cr, chunk| {
+            future::ready(Some(
+                chunk.map(|chunk| convert_chunk_line_breaks(chunk, pending_cr)),
+            ))
+        })
+}
+
+fn convert_chunk_line_breaks(chunk: Option<Bytes>, pending_cr: &mut bool) -> Bytes {
+    let Some(chunk) = chunk else {
+        return if std::mem::take(pending_cr) {
+            Bytes::from_static(b"\r")
+        } else {
+            Bytes::new()
+        };
+    };
+
+    let mut converted = Vec::with_capacity(chunk.len() + 1);
+    let mut bytes = chunk.iter().copied().peekable();
+    if std::mem::take(pending_cr) && bytes.peek() != Some(&b'\n') {
+        converted.push(b'\r');
+    }
+    while let Some(byte) = bytes.next() {
+        if byte == b'\r' {
+            match bytes.peek() {
+                Some(b'\n') => continue,
+                None => {
+                    *pending_cr = true;
+                    continue;
+                }
+                Some(_) => {}
+            }
+        }
+        converted.push(byte);
+    }
+
+    converted.into()
+}
+
+/// Writes out `manifest` and then `body` as the only part of the share in
+/// `share_directory`, finalizing it once the whole body is in.
+async fn write_share_in_single_part<S>(
+    share_directory: &RelativePath,
+    manifest: &Manifest,
+    body: S,
+    key: Option<&ShareKey>,
+    settings: &Settings,
+    config: &Config,
+    storage: &Operator,
+) -> Result<(), PostError>
+where
+    S: Stream<Item = opendal::Result<Bytes>>,
+{
+    // The size isn't known until the whole body has been streamed through.
+    write_manifest(share_directory, manifest, storage)
+        .await
+        .map_err(|err| PostError::Unkown(err.into()))?;
+
+    let written_file = write_file(
+        &part_path(share_directory, 0),
+        body,
+        WriteFileOptions {
+            codec: settings.codec_for(share_directory),
+            key,
+            buffer_size: Some(config.write_buffer_size),
+            ..Default::default()
+        },
+        storage,
+    )
+    .await
+    .map_err(|err| PostError::Unkown(err.into()))?;
+    record_part(share_directory, 0, written_file, storage)
+        .await
+        .map_err(|err| PostError::Unkown(err.into()))?;
+    finalize_manifest(share_directory, settings.deduplicate, storage)
         .await
         .map_err(|err| PostError::Unkown(err.into()))?;
 
-    Ok(Redirect::to(&format!(
-        "/file/{uuid_string}.{extension}/view"
-    )))
+    Ok(())
 }
 
-async fn upload_file_in_parts_and_redirect<'a>(
-    file_name: &str,
+/// Groups the already shared `files` into a collection that expires along with
+/// them, returning the file name it is shared under.
+async fn share_collection(
+    files: Vec<CollectionFile>,
+    options: &ShareOptions,
+    config: &Config,
+    storage: &Operator,
+) -> Result<String, PostError> {
+    let expiration_datetime = options.expiration_datetime;
+    let collection_name = share_file_name(expiration_datetime, None);
+    let collection_directory =
+        get_directory_for_expiration(expiration_datetime, config.bucket_duration)
+            .join(&collection_name);
+
+    let collection = Collection {
+        files,
+        owner_token_digest: Some(options.owner_token.digest()),
+    };
+    write_collection(&collection_directory, &collection, storage)
+        .await
+        .map_err(|err| PostError::Unkown(err.into()))?;
+
+    tracing::info!(
+        "Shared {} files as {collection_directory}",
+        collection.files.len()
+    );
+
+    Ok(collection_name)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload_file_in_parts_and_redirect(
+    original_file_name: String,
+    size: u64,
     parts: usize,
-    expiration_datetime: DateTime<Utc>,
+    checksum: Option<String>,
+    end_to_end: bool,
+    options: &ShareOptions,
+    hx_request: bool,
+    settings: &Settings,
+    config: &Config,
     storage: &Operator,
-) -> Result<Markup, PostError> {
-    let extension = RelativePath::new(&file_name)
-        .extension()
-        .ok_or(PostError::UnknownFileType)?
-        .to_string();
+    signer: &Signer,
+) -> Result<Response, PostError> {
+    let expiration_datetime = options.expiration_datetime;
+    let directory = get_directory_for_expiration(expiration_datetime, config.bucket_duration);
+    let file_name = share_file_name(
+        expiration_datetime,
+        RelativePath::new(&original_file_name).extension(),
+    );
 
-    let directory = get_directory_for_expiration(expiration_datetime);
-    let uuid_string = expiration_datetime.generate_uuidv7().to_string();
-    let file_name = format!("{uuid_string}.{extension}");
-    let file_path = directory.join(format!("{file_name}/"));
-    storage
-        .create_dir(file_path.as_str())
+    // Blobs are stored unencrypted, so can only be shared when not encrypting.
+    let encrypt = settings.encrypt || end_to_end;
+    if let Some(checksum) = checksum.filter(|_| settings.deduplicate && !encrypt) {
+        let share_directory = directory.join(&file_name);
+        if share_existing_blob(
+            &share_directory,
+            original_file_name.clone(),
+            size,
+            &checksum,
+            options,
+            storage,
+        )
+        .await?
+        {
+            return redirect_to_view(&file_name, None, options, hx_request);
+        }
+    }
+
+    // Files that are already encrypted in the browser aren't encrypted again,
+    // the server never sees the key to them anyways.
+    let key = (settings.encrypt && !end_to_end).then(ShareKey::generate);
+    let manifest = Manifest {
+        key_digest: key.as_ref().map(ShareKey::digest),
+        end_to_end_encrypted: end_to_end,
+        max_downloads: options.max_downloads,
+        owner_token_digest: Some(options.owner_token.digest()),
+        password_hash: options.password_hash.clone(),
+        ..Manifest::new(original_file_name, size, parts)
+    };
+    write_manifest(&directory.join(&file_name), &manifest, storage)
         .await
         .map_err(|err| PostError::Unkown(err.into()))?;
 
-    Ok(html!(
+    // The count was checked against the size, so the token holds that rather
+    // than whatever the form said.
+    let token = signer.sign(&UploadSession {
+        file_name: file_name.clone(),
+        parts: manifest.parts.len(),
+        max_size: size,
+        expires_at: expiration_datetime,
+        key: key.clone(),
+    });
+    let view_url = view_url(&file_name, key.as_ref());
+    // Quoted properly, so none of them can ever end the strings they're in.
+    let finalize_arguments = [&file_name, &token, &view_url]
+        .map(|argument| serde_json::to_string(argument).unwrap_or_default())
+        .join(", ");
+
+    let part_uploaders = html!(
         div id="part-uploaders" hx-swap-oob="true"
             _=(format!("
                 init set $completedParts to 0
@@ -205,6 +793,7 @@ async fn upload_file_in_parts_and_redirect<'a>(
                 hx-encoding="multipart/form-data"
                 _=(format!("
                     init get cloneNode('file', 'part-{part}') then put it at end of me
+                        then call preparePart(me) then send checksummed to me
                     on htmx:configRequest(event) js configPartRequest(event) end
                     on htmx:xhr:progress(loaded, total, detail)
                         if detail.elt is me
@@ -218,15 +807,17 @@ async fn upload_file_in_parts_and_redirect<'a>(
                         else
                             increment $completedParts
                             if $completedParts is {parts}
-                                 go to url /file/{file_name}/view
+                                call finalizeUpload({finalize_arguments})
                             end
                         end
                 "))
-                hx-trigger="submit, load delay:0.1s"
+                hx-trigger="submit, checksummed"
                 hx-target=(format!("#error-for-part-{part}"))
                 hx-swap="innerhtml"
                 {
+                    input name="Token" value=(token) hidden=(true) {}
                     input name="Part" value=(part) hidden=(true) {}
+                    input name="Checksum" value="" hidden=(true) {}
                     span {
                         sub {
                             "Uploading part " (part) "..."
@@ -236,8 +827,69 @@ async fn upload_file_in_parts_and_redirect<'a>(
                     div id=(format!("error-for-part-{part}")) {}
                 }
             }
+            div id="error-for-finalize" {}
         }
-    ))
+    );
+
+    Ok((
+        options.owner_cookie(&file_name),
+        HxReswap(axum_htmx::SwapOption::None),
+        part_uploaders,
+    )
+        .into_response())
+}
+
+/// Shares content that is already stored in a blob instead of uploading it again,
+/// returning whether there was a matching blob.
+///
+/// Note that this trusts the client to actually have the content it claims the
+/// checksum of, which is the usual tradeoff of deduplicating across uploads.
+async fn share_existing_blob(
+    share_directory: &RelativePath,
+    original_file_name: String,
+    size: u64,
+    checksum: &str,
+    options: &ShareOptions,
+    storage: &Operator,
+) -> Result<bool, PostError> {
+    let Some(key) = blob_key(checksum) else {
+        return Ok(false);
+    };
+    let blob_manifest = match reference_blob(&key, size, share_directory, storage).await {
+        Ok(blob_manifest) => blob_manifest,
+        Err(BlobError::NotFound | BlobError::SizeMismatch) => return Ok(false),
+        Err(err) => return Err(PostError::Unkown(err.into())),
+    };
+
+    let manifest = Manifest {
+        original_name: original_file_name,
+        size: blob_manifest.size,
+        parts: blob_manifest.parts.into_iter().map(Some).collect(),
+        checksum: Some(checksum.to_string()),
+        finalized: true,
+        blob: Some(key),
+        key_digest: None,
+        end_to_end_encrypted: false,
+        max_downloads: options.max_downloads,
+        owner_token_digest: Some(options.owner_token.digest()),
+        password_hash: options.password_hash.clone(),
+        content_type: blob_manifest.content_type,
+    };
+    write_manifest(share_directory, &manifest, storage)
+        .await
+        .map_err(|err| PostError::Unkown(err.into()))?;
+
+    tracing::info!("Shared {share_directory} from an existing blob");
+
+    Ok(true)
+}
+
+/// Link to view `file_name`, which has to include its key if it is encrypted.
+fn view_url(file_name: &str, key: Option<&ShareKey>) -> String {
+    match key {
+        Some(key) => format!("/file/{file_name}/view?key={key}"),
+        None => format!("/file/{file_name}/view"),
+    }
 }
 
 async fn get_and_validate_multipart_field<'a>(
@@ -263,3 +915,74 @@ async fn get_next_multipart_field(
         .await
         .map_err(|err| PostError::Unkown(err.into()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(chunks: &[&'static str]) -> String {
+        let body = stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok::<_, ()>(Bytes::from_static(chunk.as_bytes()))),
+        );
+        let converted: Vec<Bytes> =
+            futures::executor::block_on(convert_line_breaks(body).try_collect()).unwrap();
+        String::from_utf8(converted.concat()).unwrap()
+    }
+
+    #[test]
+    fn only_crlf_line_breaks_are_converted() {
+        assert_eq!(convert(&["a\r\nb\r\n"]), "a\nb\n");
+        assert_eq!(convert(&["a\rb\n\r"]), "a\rb\n\r");
+        assert_eq!(convert(&["a\r", "\nb"]), "a\nb");
+        assert_eq!(convert(&["a\r", "b\r", "", "\r"]), "a\rb\r\r");
+        assert_eq!(convert(&[]), "");
+    }
+
+    #[test]
+    fn parts_have_to_match_the_size() {
+        let config = Config {
+            part_size: 10,
+            ..Default::default()
+        };
+
+        assert_eq!(validate_parts(1, 0, false, &config).unwrap(), 1);
+        assert_eq!(validate_parts(1, 10, false, &config).unwrap(), 1);
+        assert_eq!(validate_parts(2, 11, false, &config).unwrap(), 2);
+        assert!(validate_parts(1, 11, false, &config).is_err());
+        assert!(validate_parts(3, 11, false, &config).is_err());
+        assert!(validate_parts(usize::MAX, u64::MAX, false, &config).is_err());
+    }
+
+    #[test]
+    fn end_to_end_parts_include_their_overhead() {
+        let config = Config {
+            part_size: 10,
+            ..Default::default()
+        };
+        let overhead = END_TO_END_PART_OVERHEAD;
+
+        assert_eq!(validate_parts(1, overhead, true, &config).unwrap(), 1);
+        assert_eq!(
+            validate_parts(2, 11 + 2 * overhead, true, &config).unwrap(),
+            2
+        );
+        assert!(validate_parts(1, 11 + overhead, true, &config).is_err());
+        assert!(validate_parts(1, 0, true, &config).is_err());
+    }
+
+    #[test]
+    fn parts_are_capped() {
+        let config = Config {
+            part_size: 1,
+            ..Default::default()
+        };
+
+        assert!(validate_parts(MAX_PARTS, MAX_PARTS as u64, false, &config).is_ok());
+        assert!(matches!(
+            validate_parts(MAX_PARTS + 1, MAX_PARTS as u64 + 1, false, &config),
+            Err(PostError::TooManyParts(MAX_PARTS))
+        ));
+    }
+}
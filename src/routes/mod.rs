@@ -1,3 +1,4 @@
+pub mod blob;
 pub mod file;
 pub mod index;
 pub mod not_found;
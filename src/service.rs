@@ -1,25 +1,122 @@
-use std::net::SocketAddr;
+use std::{path::PathBuf, str::FromStr, sync::Arc};
 
-use axum::Router;
-use shuttle_runtime::Service;
-use tokio_cron_scheduler::JobScheduler;
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{get, post},
+    Router,
+};
+use chrono::Utc;
+use opendal::Operator;
+use tokio::net::TcpListener;
+use tokio_cron_scheduler::{Job, JobScheduler, JobSchedulerError};
+use tower_http::{services::ServeDir, trace::TraceLayer};
+
+use crate::{
+    cleanup::cleanup,
+    config::Config,
+    routes::{self, not_found::not_found},
+    session::Signer,
+    state::{AppState, Settings},
+};
 
 pub struct TempShareService {
     pub router: Router,
     pub scheduler: JobScheduler,
 }
 
-#[shuttle_runtime::async_trait]
-impl Service for TempShareService {
-    async fn bind(mut self, addr: SocketAddr) -> Result<(), shuttle_runtime::Error> {
-        let tcp_listener = shuttle_runtime::tokio::net::TcpListener::bind(addr)
-            .await
-            .map_err(|err| shuttle_runtime::Error::BindPanic(err.to_string()))?;
+impl TempShareService {
+    /// Sets up the router and schedules the cleanup of expired files, which is
+    /// the same whichever way the service ends up being run.
+    pub async fn new(
+        storage: Operator,
+        signer: Signer,
+        settings: Settings,
+        config: Config,
+    ) -> Result<Self, JobSchedulerError> {
+        let cleanup_schedule = config.cleanup_schedule.clone();
+
+        let router = Router::new()
+            .route("/", get(routes::index::get).post(routes::index::post))
+            .route(
+                "/file/:file_name",
+                get(routes::file::index::get).post(routes::file::index::post),
+            )
+            .layer(DefaultBodyLimit::disable())
+            .route(
+                "/file/:file_name/finalize",
+                post(routes::file::finalize::post),
+            )
+            .route("/file/:file_name/view", get(routes::file::view::get))
+            .route(
+                "/file/:file_name/archive.zip",
+                get(routes::file::archive::get),
+            )
+            .route("/file/:file_name/unlock", post(routes::file::unlock::post))
+            .route("/file/:file_name/manage", get(routes::file::manage::get))
+            .route("/file/:file_name/delete", post(routes::file::delete::post))
+            .route(
+                "/file/:file_name/expiration",
+                post(routes::file::expiration::post),
+            )
+            .route("/blob/:key", get(routes::blob::get))
+            .nest_service(
+                "/public",
+                ServeDir::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("public")),
+            )
+            .layer(TraceLayer::new_for_http())
+            .with_state(AppState {
+                storage: storage.clone(),
+                signer,
+                settings,
+                config: Arc::new(config),
+            })
+            .fallback(not_found);
+
+        let scheduler = JobScheduler::new().await?;
+
+        let schedule = cron::Schedule::from_str(&cleanup_schedule).unwrap();
+        tracing::info!("Upcoming fire times:");
+        schedule
+            .upcoming(Utc)
+            .take(10)
+            .for_each(|time| tracing::info!("-> {}", time));
+
+        scheduler
+            .add(Job::new_async(
+                cleanup_schedule.as_str(),
+                move |_uuid, _l| {
+                    let storage = storage.clone(); // Clone storage just for this task
+                    Box::pin(async move {
+                        if let Err(err) = cleanup(storage).await {
+                            tracing::error!("{err}");
+                        }
+                    })
+                },
+            )?)
+            .await?;
+
+        Ok(Self { router, scheduler })
+    }
+
+    /// Serves the router on `tcp_listener` while running the scheduled jobs.
+    pub async fn serve(self, tcp_listener: TcpListener) {
         let server = async move { axum::serve(tcp_listener, self.router).await };
 
         let scheduler = self.scheduler.start();
 
         let (_scheduler_handle, _server_handle) = tokio::join!(server, scheduler);
+    }
+}
+
+#[cfg(feature = "shuttle")]
+#[shuttle_runtime::async_trait]
+impl shuttle_runtime::Service for TempShareService {
+    async fn bind(self, addr: std::net::SocketAddr) -> Result<(), shuttle_runtime::Error> {
+        let tcp_listener = TcpListener::bind(addr)
+            .await
+            .map_err(|err| shuttle_runtime::Error::BindPanic(err.to_string()))?;
+
+        self.serve(tcp_listener).await;
 
         Ok(())
     }
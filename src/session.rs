@@ -0,0 +1,228 @@
+use std::sync::Arc;
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::encryption::ShareKey;
+
+/// Something that can be handed out to clients inside a signed token.
+pub trait Claims: Serialize + DeserializeOwned {
+    /// Mixed into the signature so a token for one purpose can't be used for another.
+    const PURPOSE: &'static str;
+
+    fn expires_at(&self) -> DateTime<Utc>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TokenError {
+    #[error("Token is malformed.")]
+    Malformed,
+    #[error("Token signature is invalid.")]
+    InvalidSignature,
+    #[error("Token has expired.")]
+    Expired,
+}
+
+/// Signs and verifies tokens with a server side secret, so that anything
+/// inside of them can be trusted once they come back.
+#[derive(Clone)]
+pub struct Signer {
+    secret: Arc<[u8]>,
+}
+
+impl Signer {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// A signer with a random secret, meaning tokens won't survive a restart.
+    pub fn random() -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::new(&secret)
+    }
+
+    fn mac<C: Claims>(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(C::PURPOSE.as_bytes());
+        mac.update(b".");
+        mac.update(payload);
+        mac
+    }
+
+    pub fn sign<C: Claims>(&self, claims: &C) -> String {
+        let payload =
+            BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).expect("claims serialize"));
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .encode(self.mac::<C>(payload.as_bytes()).finalize().into_bytes());
+
+        format!("{payload}.{signature}")
+    }
+
+    pub fn verify<C: Claims>(&self, token: &str) -> Result<C, TokenError> {
+        let (payload, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+
+        self.mac::<C>(payload.as_bytes())
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::InvalidSignature)?;
+
+        let claims: C = BASE64_URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or(TokenError::Malformed)?;
+
+        if Utc::now() >= claims.expires_at() {
+            Err(TokenError::Expired)
+        } else {
+            Ok(claims)
+        }
+    }
+}
+
+/// Issued when a multipart upload starts, and required to upload each of its
+/// parts and to finalize it.
+///
+/// Since the token is only ever readable by the page that started the upload,
+/// it also protects the part forms from cross site request forgery.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadSession {
+    pub file_name: String,
+    /// How many parts the file is split into, which is checked against its
+    /// size before the token is signed.
+    pub parts: usize,
+    pub max_size: u64,
+    pub expires_at: DateTime<Utc>,
+    /// Key to encrypt the parts with, if they should be.
+    #[serde(default)]
+    pub key: Option<ShareKey>,
+}
+
+impl UploadSession {
+    /// The most any single part is allowed to be, which matches how the client
+    /// splits up files.
+    pub fn max_part_size(&self) -> u64 { self.max_size.div_ceil(self.parts.max(1) as u64) }
+}
+
+impl Claims for UploadSession {
+    const PURPOSE: &'static str = "upload-session";
+
+    fn expires_at(&self) -> DateTime<Utc> { self.expires_at }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct OtherClaims {
+        file_name: String,
+        expires_at: DateTime<Utc>,
+    }
+
+    impl Claims for OtherClaims {
+        const PURPOSE: &'static str = "other";
+
+        fn expires_at(&self) -> DateTime<Utc> { self.expires_at }
+    }
+
+    fn upload_session(expires_at: DateTime<Utc>) -> UploadSession {
+        UploadSession {
+            file_name: "file.txt".to_string(),
+            parts: 3,
+            max_size: 10,
+            expires_at,
+            key: None,
+        }
+    }
+
+    #[test]
+    fn signed_claims_verify() {
+        let signer = Signer::random();
+        let token = signer.sign(&upload_session(Utc::now() + TimeDelta::hours(1)));
+
+        let claims = signer.verify::<UploadSession>(&token).unwrap();
+        assert_eq!(claims.file_name, "file.txt");
+        assert_eq!(claims.parts, 3);
+    }
+
+    #[test]
+    fn tampered_claims_are_rejected() {
+        let signer = Signer::random();
+        let token = signer.sign(&upload_session(Utc::now() + TimeDelta::hours(1)));
+        let (_, signature) = token.split_once('.').unwrap();
+
+        let mut forged = upload_session(Utc::now() + TimeDelta::hours(1));
+        forged.parts = 1000;
+        let forged_payload = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        assert!(matches!(
+            signer.verify::<UploadSession>(&format!("{forged_payload}.{signature}")),
+            Err(TokenError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn tokens_from_another_secret_are_rejected() {
+        let token = Signer::new(b"one").sign(&upload_session(Utc::now() + TimeDelta::hours(1)));
+
+        assert!(matches!(
+            Signer::new(b"other").verify::<UploadSession>(&token),
+            Err(TokenError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn tokens_are_bound_to_their_purpose() {
+        let signer = Signer::random();
+        let token = signer.sign(&OtherClaims {
+            file_name: "file.txt".to_string(),
+            expires_at: Utc::now() + TimeDelta::hours(1),
+        });
+
+        assert!(matches!(
+            signer.verify::<UploadSession>(&token),
+            Err(TokenError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let signer = Signer::random();
+        let token = signer.sign(&upload_session(Utc::now() - TimeDelta::seconds(1)));
+
+        assert!(matches!(
+            signer.verify::<UploadSession>(&token),
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let signer = Signer::random();
+
+        for token in ["", "no-dot", "a.!!!"] {
+            assert!(matches!(
+                signer.verify::<UploadSession>(token),
+                Err(TokenError::Malformed)
+            ));
+        }
+    }
+
+    #[test]
+    fn parts_are_at_most_an_even_share_of_the_size() {
+        let session = upload_session(Utc::now());
+        assert_eq!(session.max_part_size(), 4);
+    }
+}
@@ -0,0 +1,124 @@
+//! Running the service on its own, without the Shuttle runtime, configured
+//! through command line flags or the environment.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Parser, ValueEnum};
+use opendal::{services, Operator};
+
+use crate::{session::Signer, state::Settings};
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Address to listen on.
+    #[arg(long, env = "BIND_ADDRESS", default_value = "0.0.0.0:8000")]
+    pub bind: SocketAddr,
+    /// TOML file to load the config from, if it exists. `SHARING_` environment
+    /// variables override what is in it.
+    #[arg(long, env = "CONFIG_FILE", default_value = "config.toml")]
+    pub config: PathBuf,
+    #[command(flatten)]
+    pub storage: StorageArgs,
+    /// Secret upload sessions are signed with, so uploads in progress survive a restart.
+    #[arg(long, env = "UPLOAD_SESSION_SECRET", hide_env_values = true)]
+    pub upload_session_secret: Option<String>,
+    /// Store identical uploads only once.
+    #[arg(long, env = "DEDUPLICATE")]
+    pub deduplicate: bool,
+    /// Compress compressible files at rest.
+    #[arg(long, env = "COMPRESS")]
+    pub compress: bool,
+    /// Encrypt files at rest with a key that only their links hold. Encrypted
+    /// files have no digest, so they can't be deduplicated.
+    #[arg(long, env = "ENCRYPT")]
+    pub encrypt: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum StorageBackend {
+    /// A local directory.
+    Fs,
+    /// Memory, so everything is gone after a restart.
+    Memory,
+    /// An S3 compatible bucket, such as MinIO.
+    S3,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct StorageArgs {
+    /// Where shared files are stored.
+    #[arg(long = "storage", env = "STORAGE", value_enum, default_value = "fs")]
+    pub backend: StorageBackend,
+    /// Directory files are stored in for `fs`, or the prefix within the bucket for `s3`.
+    #[arg(long = "storage-root", env = "STORAGE_ROOT")]
+    pub root: Option<String>,
+    #[arg(long, env = "S3_BUCKET", required_if_eq("backend", "s3"))]
+    pub s3_bucket: Option<String>,
+    /// Endpoint of the S3 compatible service, such as `http://localhost:9000` for MinIO.
+    #[arg(long, env = "S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+    #[arg(long, env = "S3_REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+    #[arg(long, env = "S3_ACCESS_KEY_ID")]
+    pub s3_access_key_id: Option<String>,
+    #[arg(long, env = "S3_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub s3_secret_access_key: Option<String>,
+}
+
+impl StorageArgs {
+    pub fn operator(&self) -> anyhow::Result<Operator> {
+        let operator = match self.backend {
+            StorageBackend::Fs => {
+                let mut builder = services::Fs::default();
+                builder.root(self.root.as_deref().unwrap_or("data"));
+                Operator::new(builder)?.finish()
+            }
+            StorageBackend::Memory => Operator::new(services::Memory::default())?.finish(),
+            StorageBackend::S3 => {
+                let mut builder = services::S3::default();
+                builder.region(&self.s3_region);
+                if let Some(bucket) = &self.s3_bucket {
+                    builder.bucket(bucket);
+                }
+                if let Some(root) = &self.root {
+                    builder.root(root);
+                }
+                if let Some(endpoint) = &self.s3_endpoint {
+                    builder.endpoint(endpoint);
+                }
+                if let Some(access_key_id) = &self.s3_access_key_id {
+                    builder.access_key_id(access_key_id);
+                }
+                if let Some(secret_access_key) = &self.s3_secret_access_key {
+                    builder.secret_access_key(secret_access_key);
+                }
+                Operator::new(builder)?.finish()
+            }
+        };
+
+        Ok(operator)
+    }
+}
+
+impl Args {
+    pub fn signer(&self) -> Signer {
+        match &self.upload_session_secret {
+            Some(secret) => Signer::new(secret.as_bytes()),
+            None => {
+                tracing::warn!(
+                    "No UPLOAD_SESSION_SECRET set, uploads in progress won't survive a restart"
+                );
+                Signer::random()
+            }
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            deduplicate: self.deduplicate,
+            compress: self.compress,
+            encrypt: self.encrypt,
+        }
+    }
+}
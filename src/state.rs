@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+use opendal::Operator;
+use relative_path::RelativePath;
+
+use crate::{
+    compression::{is_compressible, Codec},
+    config::Config,
+    session::Signer,
+};
+
+/// Everything the routes need, each part of which can be extracted on its own.
+#[derive(Clone)]
+pub struct AppState {
+    pub storage: Operator,
+    pub signer: Signer,
+    pub settings: Settings,
+    pub config: Arc<Config>,
+}
+
+/// Optional behaviour that can be switched on per deployment.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Settings {
+    /// Store identical uploads only once, see [`crate::blobs`].
+    pub deduplicate: bool,
+    /// Compress compressible files at rest.
+    pub compress: bool,
+    /// Encrypt files at rest with a key that only their links hold. Encrypted
+    /// files don't get a `Repr-Digest` and aren't deduplicated.
+    pub encrypt: bool,
+}
+
+impl Settings {
+    /// How the parts of `file_name` should be compressed at rest, if at all.
+    pub fn codec_for(&self, file_name: &RelativePath) -> Option<Codec> {
+        (self.compress && is_compressible(file_name)).then_some(Codec::Zstd)
+    }
+}
+
+impl FromRef<AppState> for Operator {
+    fn from_ref(state: &AppState) -> Self { state.storage.clone() }
+}
+
+impl FromRef<AppState> for Signer {
+    fn from_ref(state: &AppState) -> Self { state.signer.clone() }
+}
+
+impl FromRef<AppState> for Settings {
+    fn from_ref(state: &AppState) -> Self { state.settings }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self { state.config.clone() }
+}
@@ -0,0 +1,162 @@
+//! Shares can be removed before they expire, in which case a tombstone is left
+//! in their place so they can be told apart from ones that expired or never
+//! existed.
+
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use opendal::Operator;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blobs::dereference_blob,
+    collection::{files_directory, Collection},
+    manifest::{lock_manifest, read_manifest, Manifest, ManifestError},
+};
+
+const TOMBSTONE_FILE_NAME: &str = "tombstone.json";
+
+/// Why a share was removed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Removal {
+    /// It was downloaded as many times as it was allowed to be.
+    Consumed,
+    /// Whoever shared it deleted it.
+    Deleted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tombstone {
+    pub removal: Removal,
+    pub removed_at: DateTime<Utc>,
+}
+
+fn tombstone_path(share_directory: &RelativePath) -> RelativePathBuf {
+    share_directory.join(TOMBSTONE_FILE_NAME)
+}
+
+/// Removes everything in `share_directory` (and its reference to a blob, if it
+/// has one) and leaves a tombstone in its place.
+pub async fn remove_share(
+    share_directory: &RelativePath,
+    manifest: &Manifest,
+    removal: Removal,
+    storage: &Operator,
+) -> Result<(), ManifestError> {
+    let _guard = lock_manifest(share_directory).await;
+
+    storage.remove_all(&format!("{share_directory}/")).await?;
+    if let Some(key) = &manifest.blob {
+        dereference_blob(key, share_directory, storage).await?;
+    }
+    write_tombstone(share_directory, removal, storage).await?;
+
+    tracing::info!("Removed {share_directory} ({removal:?})");
+
+    Ok(())
+}
+
+/// Removes every file in the collection in `collection_directory` that is
+/// still around and then the collection itself, leaving tombstones in their
+/// place.
+pub async fn remove_collection(
+    collection_directory: &RelativePath,
+    collection: &Collection,
+    removal: Removal,
+    storage: &Operator,
+) -> Result<(), ManifestError> {
+    for file in &collection.files {
+        let share_directory = files_directory(collection_directory).join(&file.file_name);
+        match read_manifest(&share_directory, storage).await {
+            Ok(manifest) => remove_share(&share_directory, &manifest, removal, storage).await?,
+            Err(ManifestError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let _guard = lock_manifest(collection_directory).await;
+    storage
+        .remove_all(&format!("{collection_directory}/"))
+        .await?;
+    write_tombstone(collection_directory, removal, storage).await?;
+
+    tracing::info!("Removed {collection_directory} ({removal:?})");
+
+    Ok(())
+}
+
+async fn write_tombstone(
+    share_directory: &RelativePath,
+    removal: Removal,
+    storage: &Operator,
+) -> Result<(), ManifestError> {
+    let tombstone = Tombstone {
+        removal,
+        removed_at: Utc::now(),
+    };
+    storage
+        .write_with(
+            tombstone_path(share_directory).as_str(),
+            serde_json::to_vec(&tombstone)?,
+        )
+        .content_type("application/json")
+        .await?;
+    Ok(())
+}
+
+pub async fn read_tombstone(
+    share_directory: &RelativePath,
+    storage: &Operator,
+) -> Result<Option<Tombstone>, ManifestError> {
+    match storage.read(tombstone_path(share_directory).as_str()).await {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == opendal::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Removes the share once `body` is done with, whether it was streamed to the
+/// end or dropped along the way.
+pub fn remove_share_after<S>(
+    body: S,
+    share_directory: RelativePathBuf,
+    manifest: Manifest,
+    removal: Removal,
+    storage: Operator,
+) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+{
+    let guard = RemoveOnDrop {
+        share_directory,
+        manifest,
+        removal,
+        storage,
+    };
+    body.map(move |item| {
+        let _guard = &guard;
+        item
+    })
+}
+
+struct RemoveOnDrop {
+    share_directory: RelativePathBuf,
+    manifest: Manifest,
+    removal: Removal,
+    storage: Operator,
+}
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let share_directory = std::mem::take(&mut self.share_directory);
+        let manifest = self.manifest.clone();
+        let removal = self.removal;
+        let storage = self.storage.clone();
+        tokio::spawn(async move {
+            if let Err(err) = remove_share(&share_directory, &manifest, removal, &storage).await {
+                tracing::error!("Unable to remove {share_directory}: {err}");
+            }
+        });
+    }
+}
@@ -1,15 +1,28 @@
-use axum::extract::{multipart::Field, Multipart};
+use axum::{
+    body::Bytes,
+    extract::{multipart::Field, Multipart},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
 use chrono::{DateTime, DurationRound, TimeDelta, TimeZone, Utc};
-use futures::Stream;
+use futures::{future::Either, Stream, TryStreamExt};
 use opendal::Operator;
-use relative_path::{RelativePath, RelativePathBuf};
+use relative_path::{Component, RelativePath, RelativePathBuf};
+use sha2::{Digest, Sha256};
 use uuid::{NoContext, Timestamp, Uuid};
 
-pub fn get_directory_for_expiration(datetime: DateTime<Utc>) -> RelativePathBuf {
-    let duration = TimeDelta::hours(1);
+use crate::{
+    compression::{compress, Codec},
+    encryption::{encrypt, ShareKey},
+};
 
-    (datetime + duration)
-        .duration_trunc(duration) // we essentially want to ceil
+/// The directory files expiring at `datetime` are grouped in, which is named
+/// after the end of the `bucket_duration` long span they expire in.
+pub fn get_directory_for_expiration(
+    datetime: DateTime<Utc>,
+    bucket_duration: TimeDelta,
+) -> RelativePathBuf {
+    (datetime + bucket_duration)
+        .duration_trunc(bucket_duration) // we essentially want to ceil
         .expect("no rounding error")
         .timestamp()
         .to_string()
@@ -38,6 +51,36 @@ pub fn get_expiration_for_directory(
     }
 }
 
+#[allow(clippy::enum_variant_names)]
+#[derive(thiserror::Error, Debug)]
+pub enum FileNameError {
+    #[error("Invalid filename is not UUID.EXT.")]
+    InvalidFileName,
+    #[error("UUID needs to be v7.")]
+    InvalidUUIDVersion,
+    #[error("UUID has an invalid timestamp.")]
+    InvalidUUIDTimestamp,
+}
+
+/// Reads the expiration back out of the UUIDv7 a shared file is named with.
+pub fn get_expiration_for_file_name(
+    file_name: &RelativePath,
+) -> Result<DateTime<Utc>, FileNameError> {
+    let uuid = file_name
+        .file_stem()
+        .and_then(|stem| Uuid::try_parse(stem).ok())
+        .ok_or(FileNameError::InvalidFileName)?;
+
+    let expiration_timestamp = uuid
+        .get_timestamp()
+        .ok_or(FileNameError::InvalidUUIDVersion)?;
+    let (seconds, subsec_nanos) = expiration_timestamp.to_unix();
+    match Utc.timestamp_opt(seconds as i64, subsec_nanos) {
+        chrono::offset::LocalResult::Single(datetime) => Ok(datetime),
+        _ => Err(FileNameError::InvalidUUIDTimestamp),
+    }
+}
+
 pub trait DatetimeUUIDv7GeneratorExt {
     fn generate_uuidv7(&self) -> Uuid;
 }
@@ -53,6 +96,27 @@ impl DatetimeUUIDv7GeneratorExt for chrono::DateTime<Utc> {
     }
 }
 
+/// Extensions of shared files are kept to this many characters.
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+/// Names a file shared until `expiration_datetime` after a UUIDv7 holding that
+/// expiration, keeping the `extension` it was uploaded with if it had one.
+///
+/// The name ends up in links and scripts, so only short alphanumeric
+/// extensions are kept. Anything else is dropped, the original name is kept
+/// in the manifest anyways.
+pub fn share_file_name(expiration_datetime: DateTime<Utc>, extension: Option<&str>) -> String {
+    let uuid_string = expiration_datetime.generate_uuidv7().to_string();
+    let extension = extension.filter(|extension| {
+        (1..=MAX_EXTENSION_LENGTH).contains(&extension.len())
+            && extension.bytes().all(|byte| byte.is_ascii_alphanumeric())
+    });
+    match extension {
+        Some(extension) => format!("{uuid_string}.{extension}"),
+        None => uuid_string,
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum MultipartError {
     #[error("'{0}' is required!")]
@@ -85,25 +149,384 @@ pub async fn get_next_multipart_field(
         .map_err(|err| MultipartError::Unkown(err.into()))
 }
 
+/// Most file systems don't allow names any longer than this many bytes.
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+/// Makes the name a file was uploaded with safe to keep and hand back out, by
+/// dropping any directories in front of it, control characters and whatever
+/// doesn't fit in [`MAX_FILE_NAME_LENGTH`].
+pub fn sanitize_file_name(file_name: &str) -> Option<String> {
+    // Some browsers send the whole path, with either kind of separator.
+    let file_name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let file_name = file_name
+        .chars()
+        .filter(|char| !char.is_control())
+        .collect::<String>();
+    let file_name = file_name.trim();
+
+    let mut length = file_name.len().min(MAX_FILE_NAME_LENGTH);
+    while !file_name.is_char_boundary(length) {
+        length -= 1;
+    }
+    let file_name = file_name[..length].trim_end();
+
+    match file_name {
+        "" | "." | ".." => None,
+        file_name => Some(file_name.to_string()),
+    }
+}
+
+/// Paths within a folder are kept to this many bytes, which plenty of archive
+/// formats can't go past either.
+const MAX_PATH_LENGTH: usize = 1024;
+
+/// Makes the path of a file within a folder that was uploaded safe to keep and
+/// hand back out, by sanitizing each of its components like
+/// [`sanitize_file_name`].
+///
+/// Paths that would lead outside of the folder, such as `../secret`, are
+/// rejected rather than resolved.
+pub fn sanitize_relative_path(path: &str) -> Option<RelativePathBuf> {
+    let mut sanitized = RelativePathBuf::new();
+    for component in RelativePath::new(path).components() {
+        match component {
+            Component::Normal(name) => sanitized.push(sanitize_file_name(name)?),
+            Component::CurDir => {}
+            Component::ParentDir => return None,
+        }
+    }
+
+    match sanitized.as_str().len() {
+        0 => None,
+        length if length > MAX_PATH_LENGTH => None,
+        _ => Some(sanitized),
+    }
+}
+
+/// A `Content-Disposition` header for `file_name` (RFC 6266), with a plain
+/// ASCII `filename` for old clients and the exact name as `filename*` (RFC 5987).
+pub fn content_disposition(disposition: &str, file_name: &str) -> String {
+    let fallback = file_name
+        .chars()
+        .map(|char| match char {
+            ' '..='~' if char != '"' && char != '\\' => char,
+            _ => '_',
+        })
+        .collect::<String>();
+    let encoded = file_name
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (byte as char).to_string(),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect::<String>();
+
+    format!("{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// Formats a number of bytes for humans, such as `12.3 MB`.
+pub fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for larger_unit in ["KB", "MB", "GB", "TB", "PB"] {
+        if size < 1000.0 {
+            break;
+        }
+        size /= 1000.0;
+        unit = larger_unit;
+    }
+
+    if unit == "B" {
+        format!("{bytes} {unit}")
+    } else {
+        format!("{size:.1} {unit}")
+    }
+}
+
+/// Base64 encodes a SHA-256 digest the way it is stored in manifests.
+pub fn encode_checksum(digest: impl AsRef<[u8]>) -> String { BASE64_STANDARD.encode(digest) }
+
+/// How many of the first bytes of a file are looked at to detect its type.
+const SNIFF_SIZE: usize = 8192;
+
+/// Detects the media type of a file from its first bytes, by its magic number
+/// or otherwise whether it reads as text, rather than trusting its extension.
+pub fn sniff_content_type(head: &[u8]) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+
+    // The head can cut a character in half, which doesn't make it any less text.
+    let is_text = !head.is_empty()
+        && !head.contains(&0)
+        && std::str::from_utf8(head).map_or_else(|err| err.error_len().is_none(), |_| true);
+    if is_text {
+        "text/plain; charset=utf-8".to_string()
+    } else {
+        "application/octet-stream".to_string()
+    }
+}
+
+/// What ended up in storage after a [`write_file`].
+pub struct WrittenFile {
+    /// Size of the streamed bytes, before any compression.
+    pub size: u64,
+    /// Base64 encoded SHA-256 digest of the streamed bytes, before any compression.
+    pub checksum: String,
+    pub codec: Option<Codec>,
+    pub encrypted: bool,
+    /// Size of what actually ended up in storage.
+    pub stored_size: u64,
+    /// Media type of the streamed bytes, see [`sniff_content_type`].
+    pub content_type: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WriteFileError {
+    #[error(transparent)]
+    Storage(#[from] opendal::Error),
+    #[error("Checksum mismatch, expected '{expected}' but got '{actual}'.")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("File is larger than the allowed {0} bytes.")]
+    TooLarge(u64),
+}
+
+#[derive(Default)]
+pub struct WriteFileOptions<'a> {
+    /// Abort the write if the streamed bytes don't match this checksum.
+    pub expected_checksum: Option<&'a str>,
+    /// Abort the write as soon as more than this many bytes are streamed.
+    pub max_size: Option<u64>,
+    /// Compress the bytes with this codec as they are written.
+    pub codec: Option<Codec>,
+    /// Encrypt the bytes with this key as they are written.
+    pub key: Option<&'a ShareKey>,
+    /// Buffer this many bytes before writing them out to storage.
+    pub buffer_size: Option<usize>,
+}
+
+/// Streams `body` into `file_path`.
+///
+/// If the body turns out to break any of the `options`, the write is aborted so
+/// the file never ends up in storage.
 pub async fn write_file<S, T>(
     file_path: &RelativePath,
     body: S,
+    options: WriteFileOptions<'_>,
     storage: &Operator,
-) -> Result<(), opendal::Error>
+) -> Result<WrittenFile, WriteFileError>
 where
     S: Stream<Item = opendal::Result<T>>,
     T: Into<axum::body::Bytes>,
 {
-    let mut writer = storage
-        .writer_with(file_path.as_str())
-        .buffer(625000)
-        .concurrent(1) // 50 mb so s3 doesn't whine
-        .await?;
+    let mut size = 0;
+    let mut hasher = Sha256::new();
+    let mut head = Vec::new();
+    let body = body.map_ok(Into::into).and_then(|bytes: Bytes| {
+        size += bytes.len() as u64;
+        hasher.update(&bytes);
+        if head.len() < SNIFF_SIZE {
+            head.extend_from_slice(&bytes[..bytes.len().min(SNIFF_SIZE - head.len())]);
+        }
+
+        let too_large = options.max_size.is_some_and(|max_size| size > max_size);
+        futures::future::ready(if too_large {
+            Err(opendal::Error::new(
+                opendal::ErrorKind::Unexpected,
+                "file is too large",
+            ))
+        } else {
+            Ok(bytes)
+        })
+    });
+    // Compress before encrypting, since encrypted bytes don't compress.
+    let body = body.map_err(std::io::Error::other);
+    let body = match options.codec {
+        None => Either::Left(body),
+        Some(codec) => Either::Right(compress(codec, body)),
+    };
+    let body = match options.key {
+        None => Either::Left(body),
+        Some(key) => Either::Right(encrypt(key, body)),
+    };
+    let body =
+        body.map_err(|err| opendal::Error::new(opendal::ErrorKind::Unexpected, &err.to_string()));
+
+    let mut writer = storage.writer_with(file_path.as_str()).concurrent(1);
+    if let Some(buffer_size) = options.buffer_size {
+        writer = writer.buffer(buffer_size); // so s3 doesn't whine
+    }
+    let mut writer = writer.await?;
     let sink_result = writer.sink(body).await;
+
+    let checksum = encode_checksum(hasher.finalize());
+    let error = match (options.max_size, options.expected_checksum) {
+        (Some(max_size), _) if size > max_size => Some(WriteFileError::TooLarge(max_size)),
+        (_, Some(expected)) if sink_result.is_ok() && expected != checksum => {
+            Some(WriteFileError::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual: checksum.clone(),
+            })
+        }
+        _ => None,
+    };
+
+    if let Some(error) = error {
+        // Not every service can abort a write, in which case we clean up after it.
+        if writer.abort().await.is_err() {
+            writer.close().await?;
+            storage.delete(file_path.as_str()).await?;
+        }
+
+        return Err(error);
+    }
+
     writer.close().await?;
 
     // We want to make sure the writer is closed before propagating an error,
     // which is why we don't propagate the sink result until after the close
     // operation.
-    sink_result.map(|_| ())
+    let stored_size = sink_result?;
+
+    Ok(WrittenFile {
+        size,
+        checksum,
+        codec: options.codec,
+        encrypted: options.key.is_some(),
+        stored_size,
+        content_type: sniff_content_type(&head),
+    })
+}
+
+/// Copies `from` into `to`, streaming it through this server if the service
+/// can't copy on its own.
+pub async fn copy_file(
+    from: &RelativePath,
+    to: &RelativePath,
+    storage: &Operator,
+) -> Result<(), opendal::Error> {
+    match storage.copy(from.as_str(), to.as_str()).await {
+        Err(err) if err.kind() == opendal::ErrorKind::Unsupported => {
+            let reader = storage.reader(from.as_str()).await?;
+            let mut writer = storage.writer(to.as_str()).await?;
+            let sink_result = writer
+                .sink(reader.map_err(|err| {
+                    opendal::Error::new(opendal::ErrorKind::Unexpected, &err.to_string())
+                }))
+                .await;
+            writer.close().await?;
+            sink_result.map(|_| ())
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_are_sanitized() {
+        assert_eq!(sanitize_file_name("report.pdf").unwrap(), "report.pdf");
+        assert_eq!(
+            sanitize_file_name("C:\\Users\\me\\report.pdf").unwrap(),
+            "report.pdf"
+        );
+        assert_eq!(sanitize_file_name("../../etc/passwd").unwrap(), "passwd");
+        assert_eq!(
+            sanitize_file_name(" re\nport\u{7}.pdf ").unwrap(),
+            "report.pdf"
+        );
+        for file_name in ["", "  ", ".", "..", "folder/", "\n"] {
+            assert_eq!(sanitize_file_name(file_name), None, "{file_name:?}");
+        }
+    }
+
+    #[test]
+    fn long_file_names_are_cut_between_characters() {
+        let file_name = sanitize_file_name(&"é".repeat(200)).unwrap();
+        assert_eq!(file_name, "é".repeat(MAX_FILE_NAME_LENGTH / 2));
+    }
+
+    #[test]
+    fn share_file_names_only_keep_plain_extensions() {
+        let expiration = Utc::now();
+        let kept_extension = |extension| {
+            let file_name = share_file_name(expiration, Some(extension));
+            file_name
+                .split_once('.')
+                .map(|(_, extension)| extension.to_string())
+        };
+        assert_eq!(kept_extension("txt").unwrap(), "txt");
+        assert_eq!(kept_extension("tar7z").unwrap(), "tar7z");
+        for extension in [
+            "",
+            "a'+alert(1)+'",
+            "a\"b",
+            "a?b=c",
+            "a#b",
+            "a%20b",
+            "a b",
+            "a/b",
+            "é",
+            "abcdefghijklmnopq",
+        ] {
+            assert_eq!(kept_extension(extension), None, "{extension:?}");
+        }
+        assert!(!share_file_name(expiration, None).contains('.'));
+    }
+
+    #[test]
+    fn relative_paths_are_sanitized() {
+        let sanitize = |path| sanitize_relative_path(path).map(RelativePathBuf::into_string);
+        assert_eq!(
+            sanitize("photos/2024/beach.jpg").unwrap(),
+            "photos/2024/beach.jpg"
+        );
+        assert_eq!(sanitize("./photos//beach.jpg").unwrap(), "photos/beach.jpg");
+        assert_eq!(sanitize("/photos/beach.jpg").unwrap(), "photos/beach.jpg");
+        assert_eq!(sanitize("photos/be\nach.jpg").unwrap(), "photos/beach.jpg");
+    }
+
+    #[test]
+    fn relative_paths_cannot_leave_the_folder() {
+        for path in [
+            "",
+            ".",
+            "../secret",
+            "photos/../../secret",
+            "photos/ /beach.jpg",
+        ] {
+            assert_eq!(sanitize_relative_path(path), None, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn relative_paths_are_limited_in_length() {
+        let component = "a".repeat(MAX_FILE_NAME_LENGTH);
+        let path = [component.as_str(); 4].join("/");
+        assert!(sanitize_relative_path(&path).is_some());
+        let path = [component.as_str(); 5].join("/");
+        assert_eq!(sanitize_relative_path(&path), None);
+    }
+
+    #[test]
+    fn content_disposition_keeps_the_exact_name() {
+        assert_eq!(
+            content_disposition("attachment", "report.pdf"),
+            "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            content_disposition("inline", "naïve \"résumé\".txt"),
+            "inline; filename=\"na_ve _r_sum__.txt\"; \
+             filename*=UTF-8''na%C3%AFve%20%22r%C3%A9sum%C3%A9%22.txt"
+        );
+        assert_eq!(
+            content_disposition("attachment", "a\\b;c"),
+            "attachment; filename=\"a_b;c\"; filename*=UTF-8''a%5Cb%3Bc"
+        );
+    }
 }